@@ -0,0 +1,306 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::fmt::Formatter;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Mul, Sub};
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{Error as DeError, Visitor};
+use crate::error::{Error, Result};
+
+/// Hive Engine tokens have at most 8 decimal places
+pub const MAX_PRECISION: u32 = 8;
+const SCALE: i64 = 100_000_000;
+
+/// Token quantity with a fixed number of decimal places, e.g. `0.100` for a token with precision 3.
+/// Stored as integer units of 10^-8 so sums and comparisons are exact.
+/// Serialized as a string with exactly `precision` decimal places, the format Hive Engine expects.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokenAmount {
+    units: i64,
+    precision: u32,
+}
+
+impl TokenAmount {
+    pub fn zero(precision: u32) -> TokenAmount {
+        TokenAmount {
+            units: 0,
+            precision,
+        }
+    }
+
+    pub fn precision(&self) -> u32 {
+        self.precision
+    }
+
+    /// Returns the amount in the precision of a token. Fails if the amount has more decimal places than the token.
+    pub fn with_precision(self, precision: u32) -> Result<TokenAmount> {
+        if precision > MAX_PRECISION {
+            return Err(Error::Amount(format!("Precision {} is above {}", precision, MAX_PRECISION)));
+        }
+
+        if self.units % 10i64.pow(MAX_PRECISION - precision) != 0 {
+            return Err(Error::Amount(format!("{} has more than {} decimal places", self, precision)));
+        }
+
+        Ok(TokenAmount {
+            units: self.units,
+            precision,
+        })
+    }
+
+    /// How often `per` fits into the amount, 0 for a zero or negative `per`
+    pub fn ratio(&self, per: &TokenAmount) -> i64 {
+        if per.units <= 0 {
+            return 0;
+        }
+
+        self.units.div_euclid(per.units)
+    }
+}
+
+impl FromStr for TokenAmount {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TokenAmount> {
+        let invalid = || Error::Amount(format!("Invalid amount {:?}", s));
+
+        let trimmed = s.trim();
+        let (negative, digits) = match trimmed.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, trimmed)
+        };
+        let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+
+        if (int.is_empty() && frac.is_empty()) || !int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        if frac.len() > MAX_PRECISION as usize {
+            return Err(Error::Amount(format!("{} has more than {} decimal places", s, MAX_PRECISION)));
+        }
+
+        let int_units = if int.is_empty() { 0 } else { int.parse::<i64>().map_err(|_| invalid())? };
+        let frac_units = format!("{:0<width$}", frac, width = MAX_PRECISION as usize).parse::<i64>().map_err(|_| invalid())?;
+        let units = int_units.checked_mul(SCALE).and_then(|units| units.checked_add(frac_units)).ok_or_else(invalid)?;
+
+        Ok(TokenAmount {
+            units: if negative { -units } else { units },
+            precision: frac.len() as u32,
+        })
+    }
+}
+
+impl fmt::Display for TokenAmount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let sign = if self.units < 0 { "-" } else { "" };
+        let units = self.units.unsigned_abs();
+        let int = units / SCALE as u64;
+
+        if self.precision == 0 {
+            return write!(f, "{}{}", sign, int);
+        }
+
+        let frac = format!("{:08}", units % SCALE as u64);
+        write!(f, "{}{}.{}", sign, int, &frac[..self.precision as usize])
+    }
+}
+
+impl PartialEq for TokenAmount {
+    fn eq(&self, other: &Self) -> bool {
+        self.units == other.units
+    }
+}
+
+impl Eq for TokenAmount {}
+
+impl PartialOrd for TokenAmount {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TokenAmount {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.units.cmp(&other.units)
+    }
+}
+
+impl Add for TokenAmount {
+    type Output = TokenAmount;
+
+    fn add(self, other: TokenAmount) -> TokenAmount {
+        TokenAmount {
+            units: self.units + other.units,
+            precision: self.precision.max(other.precision),
+        }
+    }
+}
+
+impl AddAssign for TokenAmount {
+    fn add_assign(&mut self, other: TokenAmount) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for TokenAmount {
+    type Output = TokenAmount;
+
+    fn sub(self, other: TokenAmount) -> TokenAmount {
+        TokenAmount {
+            units: self.units - other.units,
+            precision: self.precision.max(other.precision),
+        }
+    }
+}
+
+impl Mul<i64> for TokenAmount {
+    type Output = TokenAmount;
+
+    fn mul(self, count: i64) -> TokenAmount {
+        TokenAmount {
+            units: self.units * count,
+            precision: self.precision,
+        }
+    }
+}
+
+impl Sum for TokenAmount {
+    fn sum<I: Iterator<Item = TokenAmount>>(iter: I) -> TokenAmount {
+        iter.fold(TokenAmount::zero(0), |sum, amount| sum + amount)
+    }
+}
+
+impl Serialize for TokenAmount {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> where S: Serializer {
+        serializer.collect_str(self)
+    }
+}
+
+struct TokenAmountVisitor;
+
+impl<'de> Visitor<'de> for TokenAmountVisitor {
+    type Value = TokenAmount;
+
+    fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "a token amount like \"0.100\"")
+    }
+
+    fn visit_str<E: DeError>(self, v: &str) -> std::result::Result<TokenAmount, E> {
+        v.parse::<TokenAmount>().map_err(E::custom)
+    }
+
+    fn visit_i64<E: DeError>(self, v: i64) -> std::result::Result<TokenAmount, E> {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_u64<E: DeError>(self, v: u64) -> std::result::Result<TokenAmount, E> {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_f64<E: DeError>(self, v: f64) -> std::result::Result<TokenAmount, E> {
+        self.visit_str(&v.to_string())
+    }
+
+    /// Hive Engine leaves some quantities empty
+    fn visit_unit<E: DeError>(self) -> std::result::Result<TokenAmount, E> {
+        Ok(TokenAmount::zero(0))
+    }
+}
+
+impl<'de> Deserialize<'de> for TokenAmount {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<TokenAmount, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_any(TokenAmountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(s: &str) -> TokenAmount {
+        s.parse::<TokenAmount>().unwrap()
+    }
+
+    #[test]
+    fn parses_edge_cases() {
+        assert_eq!(amount("1."), amount("1"));
+        assert_eq!(amount("1.").precision(), 0);
+        assert_eq!(amount(".5"), amount("0.5"));
+        assert_eq!(amount("-0.5").to_string(), "-0.5");
+        assert!(amount("-0.5") < TokenAmount::zero(0));
+        assert_eq!(amount(" 0.100 ").to_string(), "0.100");
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert!("0.123456789".parse::<TokenAmount>().is_err());
+        assert!("".parse::<TokenAmount>().is_err());
+        assert!(".".parse::<TokenAmount>().is_err());
+        assert!("abc".parse::<TokenAmount>().is_err());
+        assert!("1.2a".parse::<TokenAmount>().is_err());
+        assert!("1e5".parse::<TokenAmount>().is_err());
+        assert!("+1".parse::<TokenAmount>().is_err());
+        assert!("1.2.3".parse::<TokenAmount>().is_err());
+        assert!("99999999999999999999".parse::<TokenAmount>().is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["0", "24", "1234", "0.000", "0.100", "12.345", "0.00000001", "1.23456789"] {
+            assert_eq!(amount(s).to_string(), s);
+        }
+
+        assert_eq!(amount("5").precision(), 0);
+        assert_eq!(amount("0.100").precision(), 3);
+        assert_eq!(amount("1.23456789").precision(), 8);
+    }
+
+    #[test]
+    fn with_precision() {
+        assert_eq!(amount("0.1").with_precision(3).unwrap().to_string(), "0.100");
+        assert_eq!(amount("0.100").with_precision(1).unwrap().to_string(), "0.1");
+        assert_eq!(amount("2").with_precision(0).unwrap().to_string(), "2");
+        assert!(amount("0.1234").with_precision(3).is_err());
+        assert!(amount("0.5").with_precision(0).is_err());
+        assert!(amount("1").with_precision(9).is_err());
+    }
+
+    #[test]
+    fn sums_exactly() {
+        let sum: TokenAmount = (0..10).map(|_| amount("0.100")).sum();
+        assert_eq!(sum, amount("1"));
+        assert_eq!(sum.to_string(), "1.000");
+        assert_eq!((amount("0.100") * 3).to_string(), "0.300");
+        assert_eq!((amount("1.5") - amount("0.25")).to_string(), "1.25");
+    }
+
+    #[test]
+    fn ratio() {
+        assert_eq!(amount("1234.56789").ratio(&amount("24")), 51);
+        assert_eq!(amount("48").ratio(&amount("24")), 2);
+        assert_eq!(amount("23.999").ratio(&amount("24")), 0);
+        assert_eq!(amount("100").ratio(&TokenAmount::zero(3)), 0);
+        assert_eq!(amount("100").ratio(&amount("-24")), 0);
+    }
+
+    #[test]
+    fn deserializes_strings_and_numbers() {
+        assert_eq!(serde_json::from_str::<TokenAmount>("\"0.100\"").unwrap().to_string(), "0.100");
+        assert_eq!(serde_json::from_str::<TokenAmount>("24").unwrap().to_string(), "24");
+        assert_eq!(serde_json::from_str::<TokenAmount>("-3").unwrap().to_string(), "-3");
+        assert_eq!(serde_json::from_str::<TokenAmount>("1.5").unwrap().to_string(), "1.5");
+        assert_eq!(serde_json::from_str::<TokenAmount>("null").unwrap(), TokenAmount::zero(0));
+        assert!(serde_json::from_str::<TokenAmount>("\"abc\"").is_err());
+        assert!(serde_json::from_str::<TokenAmount>("true").is_err());
+
+        let bson = mongodb::bson::Bson::String("0.100".to_string());
+        assert_eq!(mongodb::bson::from_bson::<TokenAmount>(bson).unwrap().to_string(), "0.100");
+    }
+
+    #[test]
+    fn serializes_as_string() {
+        assert_eq!(serde_json::to_string(&amount("0.100")).unwrap(), "\"0.100\"");
+        assert_eq!(serde_json::to_string(&(amount("0.1") + amount("0.005"))).unwrap(), "\"0.105\"");
+    }
+}
@@ -1,68 +1,244 @@
-use std::path::Path;
-use std::fs;
+use std::collections::HashSet;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use crate::{hive, StakingQueueAction};
-use crate::hive::{HivePost, HivePostList};
+use crate::{StakeMode, StakingQueueAction};
+use crate::amount::TokenAmount;
+use crate::error::{Error, Result};
+use crate::hive::{HivePost, HivePostList, Operation, TokenBalance, Transaction};
+use crate::beerlover::matcher::{bounded, TextFilter};
+
+pub mod matcher;
+
+/// `custom_json` id to opt out of receiving token. `{"optout": false}` opts in again.
+pub const OPTOUT_ID: &str = "beerlover_optout";
+
+pub struct BannedWords {
+    words: Vec<String>,
+    patterns: Vec<(String, Regex)>,
+}
+
+impl BannedWords {
+    /// Compiles the banned words. Without `regex` every word is matched literally.
+    /// Words only match as a whole, so `!PIZZAZZ` is not `!PIZZA`. Invalid patterns are logged and skipped.
+    pub fn new(words: Vec<String>, case_insensitive: bool, regex: bool) -> BannedWords {
+        let mut patterns: Vec<(String, Regex)> = vec![];
+
+        for word in words.iter() {
+            let pattern = if regex { word.clone() } else { regex::escape(word) };
+
+            match RegexBuilder::new(&bounded(&pattern)).case_insensitive(case_insensitive).build() {
+                Ok(re) => patterns.push((word.clone(), re)),
+                Err(e) => warn!("Ignoring invalid banned word {:?}: {}", word, e)
+            }
+        }
+
+        BannedWords {
+            words,
+            patterns,
+        }
+    }
+
+    pub fn words(&self) -> &Vec<String> {
+        &self.words
+    }
+
+    /// Returns every banned word found in the text
+    pub fn find(&self, text: &str) -> Vec<String> {
+        self.patterns.iter()
+            .filter(|(_, re)| re.is_match(text))
+            .map(|(word, _)| word.clone())
+            .collect()
+    }
+}
+
+fn one() -> i64 {
+    1
+}
+
+/// A trigger command, e.g. `!BEER` or `!BEER 3`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Command {
+    pub command: String,
+    /// Amount staked per count
+    pub amount: TokenAmount,
+    /// Shares consumed per count
+    #[serde(default = "one")]
+    pub share_cost: i64,
+    /// Maximum number of uses per giver in 24 hours
+    #[serde(default)]
+    pub daily_limit: Option<i64>,
+    /// Highest accepted count argument, larger counts are capped
+    #[serde(default = "one")]
+    pub max_count: i64,
+}
+
+impl Command {
+    /// An empty command would match between any two characters and reward every comment
+    pub fn validate(&self) -> Result<()> {
+        if self.command.trim().is_empty() {
+            return Err(Error::Config("Command names must not be empty".to_string()));
+        }
+
+        if self.max_count <= 0 || self.share_cost <= 0 {
+            return Err(Error::Config(format!("{} needs a positive max_count and share_cost", self.command)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Share allowance for users staking at least `min_stake`.
+/// `ratio` gives one share per n staked token, `max_shares` caps the tier or sets a flat allowance without a ratio.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareTier {
+    #[serde(default)]
+    pub min_stake: TokenAmount,
+    #[serde(default)]
+    pub ratio: Option<TokenAmount>,
+    #[serde(default)]
+    pub max_shares: Option<i64>,
+}
+
+impl ShareTier {
+    fn shares(&self, stake: &TokenAmount) -> i64 {
+        match (&self.ratio, self.max_shares) {
+            (Some(ratio), Some(max_shares)) => stake.ratio(ratio).min(max_shares),
+            (Some(ratio), None) => stake.ratio(ratio),
+            (None, Some(max_shares)) => max_shares,
+            (None, None) => 0
+        }
+    }
+}
+
 pub struct Beerlover {
-    banned_accounts: Vec<String>,
-    banned_words: Vec<&'static str>,
-    command: String,
-    share_ratio: f64,
+    banned_accounts: HashSet<String>,
+    banned_words: BannedWords,
+    commands: Vec<Command>,
+    command_pattern: Regex,
+    text_filter: TextFilter,
+    share_tiers: Vec<ShareTier>,
+    max_daily_shares: Option<i64>,
+    stake_mode: StakeMode,
 }
 
 impl Beerlover {
-    pub fn new(banned_accounts: Vec<String>, banned_words: Vec<&'static str>, command: String, share_ratio: f64) -> Beerlover {
+    pub fn new(banned_accounts: HashSet<String>, banned_words: BannedWords, commands: Vec<Command>, mut share_tiers: Vec<ShareTier>, max_daily_shares: Option<i64>, stake_mode: StakeMode) -> Beerlover {
+        // Longer commands first so !BEERPARTY is not read as !BEER
+        let mut names: Vec<String> = commands.iter().map(|c| regex::escape(&c.command)).collect();
+        names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+        share_tiers.sort_by_key(|tier| tier.min_stake);
+
+        let command_pattern = Regex::new(&bounded(&format!(r"({})(?:[ \t]+(\d+))?", names.join("|")))).unwrap();
+
         Beerlover {
             banned_accounts,
             banned_words,
-            command,
-            share_ratio,
+            commands,
+            command_pattern,
+            text_filter: TextFilter::new(),
+            share_tiers,
+            max_daily_shares,
+            stake_mode,
         }
     }
 
-    pub fn maxium_shares(&self, balance: f64) -> i64 {
-        (balance / self.share_ratio) as i64
+    pub fn commands(&self) -> &Vec<Command> {
+        &self.commands
+    }
+
+    pub fn command(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|c| c.command == name)
     }
 
-    pub fn filter_operations(&self, operations: serde_json::Value, tx_id: String) -> HivePostList {
-        let op_array: Option<&Vec<Value>> = operations.as_array().to_owned();
+    /// Finds the first command in the visible text of a body and its count argument
+    pub fn parse_command(&self, text: &str) -> Option<(&Command, i64)> {
+        let captures = self.command_pattern.captures(text)?;
+        let command = self.command(captures.get(1)?.as_str())?;
+
+        let count = captures.get(2)
+            .and_then(|c| c.as_str().parse::<i64>().ok())
+            .filter(|c| *c > 0)
+            .unwrap_or(1)
+            .min(command.max_count.max(1));
+
+        Some((command, count))
+    }
 
+    /// Replaces the blacklist and returns the added and removed accounts
+    pub fn set_banned_accounts(&mut self, banned_accounts: HashSet<String>) -> (Vec<String>, Vec<String>) {
+        let mut added: Vec<String> = banned_accounts.difference(&self.banned_accounts).cloned().collect();
+        let mut removed: Vec<String> = self.banned_accounts.difference(&banned_accounts).cloned().collect();
+        added.sort();
+        removed.sort();
+
+        self.banned_accounts = banned_accounts;
+
+        (added, removed)
+    }
+
+    pub fn banned_accounts(&self) -> &HashSet<String> {
+        &self.banned_accounts
+    }
+
+    pub fn banned_words(&self) -> &BannedWords {
+        &self.banned_words
+    }
+
+    pub fn set_banned_words(&mut self, banned_words: BannedWords) {
+        self.banned_words = banned_words;
+    }
+
+    /// Stake that counts toward the share allowance in the configured stake mode
+    pub fn effective_stake(&self, balance: &TokenBalance) -> TokenAmount {
+        match self.stake_mode {
+            StakeMode::Own => balance.stake,
+            StakeMode::DelegatedOut => balance.stake + balance.delegationsOut,
+            StakeMode::DelegatedIn => balance.stake + balance.delegationsIn,
+            StakeMode::All => balance.stake + balance.delegationsOut + balance.delegationsIn,
+        }
+    }
+
+    pub fn share_tiers(&self) -> &Vec<ShareTier> {
+        &self.share_tiers
+    }
+
+    /// Shares per 24 hours from the highest tier the stake reaches, limited by the daily cap
+    pub fn maxium_shares(&self, balance: &TokenBalance) -> i64 {
+        let stake = self.effective_stake(balance);
+
+        let shares = match self.share_tiers.iter().rev().find(|tier| stake >= tier.min_stake) {
+            Some(tier) => tier.shares(&stake),
+            None => 0
+        };
+
+        match self.max_daily_shares {
+            Some(max_daily_shares) => shares.min(max_daily_shares),
+            None => shares
+        }
+    }
+
+    /// Returns the comments that contain a command. Their eligibility is checked by the rules.
+    pub fn filter_operations(&self, transaction: &Transaction) -> HivePostList {
         let mut valid_posts: HivePostList = vec![];
 
-        for op in op_array.iter() {
-            let op_self: Vec<Value> = op.to_owned().to_owned();
-            let op_name: &str = op_self[0][0].as_str().unwrap(); // Possibly do one more iteration for tx with more than one op tuple
-
-            if op_name == hive::hive_ops::COMMENT  {
-                let mut post: HivePost = HivePost::from(op_self[0].to_owned(), tx_id.to_owned(), StakingQueueAction::Invalid);
-
-                if post.body.contains(self.command.clone().as_str()) {
-                    let mut valid = true;
-
-                    if self.banned_accounts.contains(&post.author) == false && self.banned_accounts.contains(&post.parent_author) == false {
-                        for word in self.banned_words.to_owned() {
-                            if post.body.contains(word) {
-                                post.action = StakingQueueAction::BlockedWord;
-                                valid_posts.push(post.clone());
-                                valid = false;
-                            }
-                        }
-                    } else {
-                        post.action = StakingQueueAction::Blocked;
-                        valid_posts.push(post.clone());
-                        valid = false;
-                    }
-
-                    if post.author == post.parent_author || post.parent_permlink == "" || post.parent_author == "" {
-                        post.action = StakingQueueAction::SelfReward;
-                        valid_posts.push(post.clone());
-                        valid = false;
-                    }
-
-                    if valid  {
-                        post.action = StakingQueueAction::StakeAndComment;
-                        valid_posts.push(post.clone());
-                    }
+        for (op_index, op) in transaction.operations.iter().enumerate() {
+            if let Operation::Malformed(name, e) = op {
+                warn!("Skipping operation: {}", Error::MalformedOperation(format!("{} {}/{}: {}", name, transaction.transaction_id, op_index, e)));
+            }
+
+            if let Operation::Comment(comment) = op {
+                let mut post: HivePost = HivePost::from(comment, transaction.transaction_id.clone(), op_index as i64, StakingQueueAction::Invalid);
+
+                post.text = self.text_filter.visible_text(&post.body);
+
+                if let Some((command, count)) = self.parse_command(&post.text) {
+                    post.command = command.command.clone();
+                    post.amount = command.amount * count;
+                    post.shares = command.share_cost * count;
+                    post.action = StakingQueueAction::StakeAndComment;
+
+                    valid_posts.push(post);
                 }
             }
         }
@@ -70,23 +246,112 @@ impl Beerlover {
         valid_posts
     }
 
-    pub fn get_start_block(&self) -> i64 {
-        let re = regex::Regex::new(r"\r?\n|\r").unwrap();
-        if Path::new("./state.dat").exists() {
-            match fs::read_to_string("./state.dat") {
-                Ok(v) => {
-                    re.replace_all(v.as_str(), "").parse::<i64>().unwrap() + 1
+    /// Returns the accounts of the opt-out operations and whether they opted out or in again
+    pub fn filter_optouts(&self, transaction: &Transaction) -> Vec<(String, bool)> {
+        let mut optouts: Vec<(String, bool)> = vec![];
+
+        for op in transaction.operations.iter() {
+            if let Operation::CustomJson(custom_json) = op {
+                if custom_json.id != OPTOUT_ID {
+                    continue;
                 }
-                Err(_) => 1
+
+                let account = match custom_json.required_posting_auths.first().or_else(|| custom_json.required_auths.first()) {
+                    Some(account) => account.clone(),
+                    None => continue
+                };
+
+                let opted_out = serde_json::from_str::<Value>(&custom_json.json).ok()
+                    .and_then(|json| json["optout"].as_bool())
+                    .unwrap_or(true);
+
+                optouts.push((account, opted_out));
             }
-        } else {
-            1
         }
+
+        optouts
     }
-    pub fn set_start_block(&self, block: i64) -> bool {
-        match fs::write("./state.dat", block.to_string()) {
-            Ok(_) => true,
-            _ => false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(name: &str, amount: &str, max_count: i64) -> Command {
+        Command {
+            command: name.to_string(),
+            amount: amount.parse().unwrap(),
+            share_cost: 1,
+            daily_limit: None,
+            max_count,
         }
     }
+
+    fn beerlover(banned_words: Vec<&str>) -> Beerlover {
+        Beerlover::new(
+            HashSet::new(),
+            BannedWords::new(banned_words.into_iter().map(str::to_string).collect(), false, false),
+            vec![command("!BEER", "0.100", 5), command("!BEERPARTY", "1.000", 1)],
+            vec![],
+            None,
+            StakeMode::Own,
+        )
+    }
+
+    fn parse(beerlover: &Beerlover, body: &str) -> Option<(String, i64)> {
+        let text = beerlover.text_filter.visible_text(body);
+        beerlover.parse_command(&text).map(|(command, count)| (command.command.clone(), count))
+    }
+
+    #[test]
+    fn parses_commands() {
+        let beerlover = beerlover(vec![]);
+
+        assert_eq!(parse(&beerlover, "Cheers !BEER"), Some(("!BEER".to_string(), 1)));
+        assert_eq!(parse(&beerlover, "!BEER 3 for you"), Some(("!BEER".to_string(), 3)));
+        assert_eq!(parse(&beerlover, "!BEER 3x"), Some(("!BEER".to_string(), 1)));
+        assert_eq!(parse(&beerlover, "!BEER 0"), Some(("!BEER".to_string(), 1)));
+        assert_eq!(parse(&beerlover, "!BEERPARTY"), Some(("!BEERPARTY".to_string(), 1)));
+    }
+
+    #[test]
+    fn ignores_longer_words_and_hidden_text() {
+        let beerlover = beerlover(vec![]);
+
+        assert_eq!(parse(&beerlover, "!BEERS all around"), None);
+        assert_eq!(parse(&beerlover, "thanks !BEERLOVER"), None);
+        assert_eq!(parse(&beerlover, "> !BEER"), None);
+        assert_eq!(parse(&beerlover, "```\n!BEER\n```"), None);
+        assert_eq!(parse(&beerlover, "type `!BEER`"), None);
+        assert_eq!(parse(&beerlover, "<!-- !BEER -->"), None);
+    }
+
+    #[test]
+    fn caps_count_at_max_count() {
+        let beerlover = beerlover(vec![]);
+
+        assert_eq!(parse(&beerlover, "!BEER 3"), Some(("!BEER".to_string(), 3)));
+        assert_eq!(parse(&beerlover, "!BEER 99"), Some(("!BEER".to_string(), 5)));
+        assert_eq!(parse(&beerlover, "!BEERPARTY 3"), Some(("!BEERPARTY".to_string(), 1)));
+    }
+
+    #[test]
+    fn banned_words_match_whole_words() {
+        let beerlover = beerlover(vec!["!PIZZA"]);
+        let words = beerlover.banned_words();
+
+        assert_eq!(words.find("!BEER !PIZZA"), vec!["!PIZZA".to_string()]);
+        assert!(words.find("!BEER !PIZZAZZ").is_empty());
+        assert!(words.find("!BEER").is_empty());
+    }
+
+    #[test]
+    fn rejects_empty_commands_and_non_positive_counts() {
+        assert!(command("!BEER", "0.100", 1).validate().is_ok());
+        assert!(command("", "0.100", 1).validate().is_err());
+        assert!(command(" \t", "0.100", 1).validate().is_err());
+        assert!(command("!BEER", "0.100", 0).validate().is_err());
+        assert!(Command { share_cost: 0, ..command("!BEER", "0.100", 1) }.validate().is_err());
+        assert!(Command { share_cost: -1, ..command("!BEER", "0.100", 1) }.validate().is_err());
+    }
 }
@@ -0,0 +1,112 @@
+use regex::Regex;
+
+/// Wraps a pattern so it only matches as a whole word.
+/// `\b` does not work for words starting with `!`, so the surrounding characters are matched instead.
+pub fn bounded(pattern: &str) -> String {
+    format!(r"(?:^|[^\w!])(?:{})(?:$|\W)", pattern)
+}
+
+/// Removes the parts of a comment body that are not written by the author:
+/// quoted lines, fenced code blocks, inline code, HTML comments and `<blockquote>`, `<pre>` and `<code>` elements.
+pub struct TextFilter {
+    ignored: Regex,
+}
+
+impl TextFilter {
+    pub fn new() -> TextFilter {
+        TextFilter {
+            ignored: Regex::new(r"(?is)<!--.*?(?:-->|\z)|<blockquote\b.*?</blockquote>|<pre\b.*?</pre>|<code\b.*?</code>|``.*?``|`[^`\n]*`").unwrap()
+        }
+    }
+
+    pub fn visible_text(&self, body: &str) -> String {
+        let mut lines: Vec<&str> = vec![];
+        let mut fence: Option<&str> = None;
+
+        for line in body.lines() {
+            let trimmed = line.trim_start();
+
+            match fence {
+                Some(marker) => {
+                    if trimmed.starts_with(marker) {
+                        fence = None;
+                    }
+                }
+                None if trimmed.starts_with("```") => fence = Some("```"),
+                None if trimmed.starts_with("~~~") => fence = Some("~~~"),
+                None if trimmed.starts_with('>') => {}
+                None => lines.push(line)
+            }
+        }
+
+        // Replace with a space so the text around a removed part is not joined into one word
+        self.ignored.replace_all(&lines.join("\n"), " ").into_owned()
+    }
+}
+
+impl Default for TextFilter {
+    fn default() -> TextFilter {
+        TextFilter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(word: &str, text: &str) -> bool {
+        Regex::new(&bounded(&regex::escape(word))).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn bounded_matches_whole_words() {
+        assert!(matches("!BEER", "!BEER"));
+        assert!(matches("!BEER", "Cheers !BEER, enjoy"));
+        assert!(matches("!BEER", "line one\n!BEER!"));
+        assert!(!matches("!BEER", "!BEERS"));
+        assert!(!matches("!BEER", "!BEERLOVER"));
+        assert!(!matches("!BEER", "x!BEER"));
+        assert!(!matches("!BEER", "!!BEER"));
+        assert!(!matches("!PIZZA", "!PIZZAZZ"));
+        assert!(matches("!PIZZA", "have a !PIZZA."));
+    }
+
+    #[test]
+    fn visible_text_ignores_quotes() {
+        let filter = TextFilter::new();
+
+        assert!(!filter.visible_text("> !BEER").contains("!BEER"));
+        assert!(!filter.visible_text("Nice!\n  > > !BEER from someone else").contains("!BEER"));
+        assert!(filter.visible_text("> quoted\n!BEER").contains("!BEER"));
+        assert!(!filter.visible_text("<blockquote>!BEER</blockquote>").contains("!BEER"));
+    }
+
+    #[test]
+    fn visible_text_ignores_code() {
+        let filter = TextFilter::new();
+
+        assert!(!filter.visible_text("```\n!BEER\n```").contains("!BEER"));
+        assert!(!filter.visible_text("~~~rust\n!BEER\n~~~").contains("!BEER"));
+        assert!(filter.visible_text("```\ncode\n```\n!BEER").contains("!BEER"));
+        assert!(!filter.visible_text("Use `!BEER` to tip").contains("!BEER"));
+        assert!(!filter.visible_text("Use ``!BEER`` to tip").contains("!BEER"));
+        assert!(!filter.visible_text("<code>!BEER</code> <pre>!BEER</pre>").contains("!BEER"));
+    }
+
+    #[test]
+    fn visible_text_ignores_html_comments() {
+        let filter = TextFilter::new();
+
+        assert!(!filter.visible_text("<!-- !BEER -->").contains("!BEER"));
+        assert!(!filter.visible_text("Hi <!--\n!BEER\n--> there").contains("!BEER"));
+        assert!(!filter.visible_text("Hi <!-- !BEER").contains("!BEER"));
+        assert!(filter.visible_text("<!-- hidden --> !BEER").contains("!BEER"));
+    }
+
+    #[test]
+    fn removed_parts_do_not_join_words() {
+        let filter = TextFilter::new();
+
+        assert!(matches("!BEER", &filter.visible_text("!BEER<!-- x -->S")));
+    }
+}
@@ -0,0 +1,108 @@
+use serde_json::{json, Value};
+use reqwest::Client;
+
+pub const HIVE_ENGINE_CHAIN_ID: &str = "ssc-mainnet-hive";
+
+/// Outcome of a request to the broadcast API
+#[derive(Debug)]
+pub enum Broadcast {
+    /// The transaction was broadcast with this id
+    Sent(String),
+    /// The request never reached the service, sending it again is safe
+    NotSent(String),
+    /// The service may have broadcast the transaction, sending it again could duplicate it
+    Unknown(String),
+}
+
+/// Client for the signing service behind `--broadcast-api-host`.
+///
+/// The service receives `{"operations": [[name, payload], ...]}`, signs and broadcasts the
+/// transaction with the key of the bot account and answers with `{"tx_id": "..."}`.
+pub struct BroadcastApi {
+    host: String,
+    http_client: Client,
+}
+
+impl BroadcastApi {
+    async fn request(&self, operations: Value) -> Broadcast {
+        let body = json!({
+            "operations": operations
+        });
+
+        let request_body = body.to_string().into_bytes();
+
+        let response = match self.http_client
+            .post(&self.host)
+            .header("Content-Type", "application/json")
+            .body(reqwest::Body::from(request_body)).send()
+            .await {
+            Ok(r) => r,
+            // A timeout or dropped connection can happen after the service already broadcast the transaction
+            Err(e) if e.is_connect() => return Broadcast::NotSent(format!("Failed to reach broadcast API: {}", e)),
+            Err(e) => return Broadcast::Unknown(format!("Broadcast API request failed: {}", e))
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            return Broadcast::Unknown(format!("Broadcast API answered with {}", status));
+        }
+
+        match response.json::<Value>().await {
+            Ok(r) => match r["tx_id"].as_str() {
+                Some(tx_id) => Broadcast::Sent(tx_id.to_string()),
+                None => Broadcast::Unknown(format!("Broadcast API did not return a transaction id: {}", r))
+            },
+            Err(e) => Broadcast::Unknown(format!("Failed to parse broadcast API response: {}", e))
+        }
+    }
+
+    pub fn new(host: String, http_client: Client) -> BroadcastApi {
+        BroadcastApi {
+            host,
+            http_client,
+        }
+    }
+
+    pub async fn stake(&self, account: String, chain_id: String, to: String, symbol: String, quantity: String) -> Broadcast {
+        let payload = json!({
+            "contractName": "tokens",
+            "contractAction": "stake",
+            "contractPayload": {
+                "to": to,
+                "symbol": symbol,
+                "quantity": quantity
+            }
+        });
+
+        self.request(json!([
+            ["custom_json", {
+                "required_auths": [account],
+                "required_posting_auths": [],
+                "id": chain_id,
+                "json": payload.to_string()
+            }]
+        ])).await
+    }
+
+    pub async fn comment(&self, account: String, parent_author: String, parent_permlink: String, permlink: String, body: String) -> Option<String> {
+        let result = self.request(json!([
+            ["comment", {
+                "parent_author": parent_author,
+                "parent_permlink": parent_permlink,
+                "author": account,
+                "permlink": permlink,
+                "title": "",
+                "body": body,
+                "json_metadata": "{}"
+            }]
+        ])).await;
+
+        match result {
+            Broadcast::Sent(tx_id) => Some(tx_id),
+            Broadcast::NotSent(e) | Broadcast::Unknown(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::f64::consts::LN_2;
+use std::hash::{Hash, Hasher};
+
+/// Set with a fixed capacity that evicts the least recently used key
+pub struct LruSet<K> {
+    capacity: usize,
+    tick: u64,
+    keys: HashMap<K, u64>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone> LruSet<K> {
+    pub fn new(capacity: usize) -> LruSet<K> {
+        LruSet {
+            capacity,
+            tick: 0,
+            keys: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+
+        if let Some(previous) = self.keys.insert(key.clone(), self.tick) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(self.tick, key.clone());
+    }
+
+    /// Returns true and marks the key as recently used if it is in the set
+    pub fn contains(&mut self, key: &K) -> bool {
+        if self.keys.contains_key(key) {
+            self.touch(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn insert(&mut self, key: K) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.touch(&key);
+
+        while self.keys.len() > self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.keys.remove(&oldest),
+                None => break
+            };
+        }
+    }
+}
+
+/// Set without false negatives: if `contains` is false the key was never inserted.
+/// A true result may be wrong at roughly `false_positive_rate` until more than `capacity` keys are inserted.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    size: u64,
+    hashes: u64,
+}
+
+impl BloomFilter {
+    pub fn new(capacity: usize, false_positive_rate: f64) -> BloomFilter {
+        let capacity = capacity.max(1) as f64;
+        let size = (-capacity * false_positive_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0) as u64;
+        let hashes = (size as f64 / capacity * LN_2).round().max(1.0) as u64;
+
+        BloomFilter {
+            bits: vec![0; size.div_ceil(64) as usize],
+            size,
+            hashes,
+        }
+    }
+
+    /// Bit positions of the key by double hashing
+    fn positions<K: Hash + ?Sized>(&self, key: &K) -> impl Iterator<Item = u64> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let first = hasher.finish();
+
+        let mut hasher = DefaultHasher::new();
+        first.hash(&mut hasher);
+        let second = hasher.finish() | 1;
+
+        let size = self.size;
+        (0..self.hashes).map(move |i| first.wrapping_add(i.wrapping_mul(second)) % size)
+    }
+
+    pub fn insert<K: Hash + ?Sized>(&mut self, key: &K) {
+        for position in self.positions(key).collect::<Vec<u64>>() {
+            self.bits[(position / 64) as usize] |= 1 << (position % 64);
+        }
+    }
+
+    pub fn contains<K: Hash + ?Sized>(&self, key: &K) -> bool {
+        self.positions(key).all(|position| self.bits[(position / 64) as usize] & (1 << (position % 64)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_filter_has_no_false_negatives() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+
+        for i in 0..2000 {
+            filter.insert(&("tx".to_string(), i as i64));
+        }
+
+        assert!((0..2000).all(|i| filter.contains(&("tx".to_string(), i as i64))));
+    }
+
+    #[test]
+    fn bloom_filter_rejects_most_unknown_keys() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+
+        for i in 0..1000 {
+            filter.insert(&("tx".to_string(), i as i64));
+        }
+
+        let false_positives = (1000..11000).filter(|i| filter.contains(&("tx".to_string(), *i as i64))).count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn lru_set_evicts_least_recently_used() {
+        let mut set = LruSet::new(2);
+        set.insert(1);
+        set.insert(2);
+        assert!(set.contains(&1));
+        set.insert(3);
+
+        assert!(set.contains(&1));
+        assert!(!set.contains(&2));
+        assert!(set.contains(&3));
+    }
+}
@@ -0,0 +1,38 @@
+use std::fs;
+use serde::{Deserialize, Serialize};
+use crate::beerlover::{Command, ShareTier};
+use crate::error::{Error, Result};
+use crate::rules::RuleConfig;
+
+/// Optional JSON configuration file passed with `--config`.
+/// Every field falls back to its default so the file only needs to contain the settings in use.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Additional banned words, merged with `--banned-words` and the Mongo collection
+    pub banned_words: Vec<String>,
+    /// Trigger commands, replaces `--trigger-word` and `--reward-amount`
+    pub commands: Vec<Command>,
+    /// Eligibility rules in evaluation order, the built-in rules are used when empty
+    pub rules: Vec<RuleConfig>,
+    /// Share allowance by stake, replaces `--share-ration`
+    pub share_tiers: Vec<ShareTier>,
+    /// Maximum shares per user in 24 hours regardless of the stake
+    pub max_daily_shares: Option<i64>,
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config> {
+        let content = fs::read_to_string(path).map_err(|e| Error::Config(format!("{}: {}", path, e)))?;
+        let config: Config = serde_json::from_str(&content).map_err(|e| Error::Config(format!("{}: {}", path, e)))?;
+
+        Ok(config)
+    }
+
+    pub fn load(path: &Option<String>) -> Result<Config> {
+        match path {
+            Some(path) => Config::from_file(path),
+            None => Ok(Config::default())
+        }
+    }
+}
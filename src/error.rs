@@ -0,0 +1,47 @@
+use std::fmt;
+use std::fmt::Formatter;
+use serde_json::Value;
+
+#[derive(Debug)]
+pub enum Error {
+    RpcTransport(String), // No node could be reached or the response was not valid JSON
+    RpcResponse(String), // The node answered with a JSON-RPC error or an unexpected result
+    IdMismatch { expected: i64, got: Value }, // The response belongs to another request
+    MalformedOperation(String), // An operation in a block is missing required fields
+    Database(mongodb::error::Error),
+    StateFile(String), // The block checkpoint could not be read or written
+    Config(String), // The config file could not be read or parsed
+    Amount(String), // A token amount is invalid or has more decimal places than the token
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Transient errors that are worth retrying the same block for
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::RpcTransport(_) | Error::RpcResponse(_) | Error::IdMismatch { .. } | Error::Database(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RpcTransport(e) => write!(f, "RPC transport error: {}", e),
+            Error::RpcResponse(e) => write!(f, "RPC error response: {}", e),
+            Error::IdMismatch { expected, got } => write!(f, "Request ID does not match! Expected {} got {}", expected, got),
+            Error::MalformedOperation(e) => write!(f, "Malformed operation: {}", e),
+            Error::Database(e) => write!(f, "Database error: {}", e),
+            Error::StateFile(e) => write!(f, "State file error: {}", e),
+            Error::Config(e) => write!(f, "Config error: {}", e),
+            Error::Amount(e) => write!(f, "Amount error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(e: mongodb::error::Error) -> Self {
+        Error::Database(e)
+    }
+}
@@ -1,9 +1,32 @@
+use std::collections::HashSet;
 use std::iter::Iterator;
+use chrono::NaiveDateTime;
+use serde::Deserialize;
 use serde_json::{json, Value};
 use reqwest::{Client};
 use crate::StakingQueueAction;
+use crate::amount::TokenAmount;
+use crate::error::{Error, Result};
+use crate::hive::rpc::{RpcClient, RpcOptions};
 
+pub mod block;
+pub mod fetcher;
 pub mod hive_ops;
+pub mod rpc;
+
+pub use block::{CommentOperation, Operation, SignedBlock, Transaction};
+
+const IGNORE_LIST_PAGE_SIZE: i64 = 1000;
+
+/// True if a JSON-RPC error says the node does not serve the method, e.g. because the block_api plugin is disabled
+fn method_not_found(error: &str) -> bool {
+    let error: Value = match serde_json::from_str(error) {
+        Ok(error) => error,
+        Err(_) => return false
+    };
+
+    error["code"] == -32601 || error["message"].as_str().is_some_and(|message| message.contains("block_api"))
+}
 
 #[derive(Debug, Clone)]
 pub struct HivePost {
@@ -12,24 +35,46 @@ pub struct HivePost {
     pub parent_author: String,
     pub parent_permlink: String,
     pub body: String,
+    /// Body without quotes, code and HTML comments
+    pub text: String,
     pub tx_id: String,
-    pub action: StakingQueueAction
+    pub op_index: i64,
+    pub command: String,
+    pub amount: TokenAmount,
+    pub shares: i64,
+    pub action: StakingQueueAction,
+    /// Every rejection reason that matched, in evaluation order
+    pub reasons: Vec<StakingQueueAction>
 }
 
 pub type HivePostList = Vec<HivePost>;
 
 impl HivePost {
-    pub fn from(op: Value, tx_id: String, action: StakingQueueAction) -> Self {
+    pub fn from(op: &CommentOperation, tx_id: String, op_index: i64, action: StakingQueueAction) -> Self {
         HivePost {
-            author: op[1]["author"].as_str().unwrap().to_string(),
-            permlink: op[1]["permlink"].as_str().unwrap().to_string(),
-            parent_author: op[1]["parent_author"].as_str().unwrap().to_string(),
-            parent_permlink: op[1]["parent_permlink"].as_str().unwrap().to_string(),
-            body: op[1]["body"].as_str().unwrap().to_string(),
+            author: op.author.clone(),
+            permlink: op.permlink.clone(),
+            parent_author: op.parent_author.clone(),
+            parent_permlink: op.parent_permlink.clone(),
+            body: op.body.clone(),
+            text: String::new(),
             tx_id,
-            action
+            op_index,
+            command: String::new(),
+            amount: TokenAmount::default(),
+            shares: 0,
+            action,
+            reasons: vec![]
         }
     }
+
+    /// Records a rejection reason. The first recorded reason decides the action.
+    pub fn reject(&mut self, reason: StakingQueueAction) {
+        if self.reasons.is_empty() {
+            self.action = reason.clone();
+        }
+        self.reasons.push(reason);
+    }
 }
 
 pub struct Counter {
@@ -50,139 +95,265 @@ impl Counter {
 }
 
 pub struct Hive {
-    rpc_host: String,
-    http_client: Client,
+    rpc: RpcClient,
     request_id_generator: Counter,
+    use_block_range: bool,
 }
 
 impl Hive {
-    async fn request(&self, body: Vec<u8>) -> Value {
-        let result = &self.http_client
-            .post(&self.rpc_host)
-            .header("Content-Type", "application/json")
-            .body(reqwest::Body::from(body)).send()
-            .await.unwrap().json::<Value>().await;
-
-        match result {
-            Ok(r) => r.to_owned(),
-            Err(_) => panic!("Failed to request Hive RPC!")
+    async fn request(&mut self, request_id: i64, body: Value) -> Result<Value> {
+        let request_body = body.to_string().into_bytes();
+
+        let result = self.rpc.request(request_body).await?;
+
+        if result["id"] != request_id {
+            return Err(Error::IdMismatch { expected: request_id, got: result["id"].clone() });
         }
+
+        Ok(result)
     }
 
-    pub fn new(rpc_host: String, http_client: Client, request_id_generator: Counter) -> Hive {
-        Hive {
-            rpc_host,
-            http_client,
+    pub fn new(rpc_hosts: Vec<String>, http_client: Client, request_id_generator: Counter, rpc_options: RpcOptions) -> Result<Hive> {
+        Ok(Hive {
+            rpc: RpcClient::new("Hive", rpc_hosts, http_client, rpc_options)?,
             request_id_generator,
+            use_block_range: true,
+        })
+    }
+
+    pub async fn get_ignore_list(&mut self, account: String) -> Result<HashSet<String>> {
+        let mut ignore_list: HashSet<String> = HashSet::new();
+        let mut start: String = String::new();
+
+        loop {
+            let request_id = self.request_id_generator.next();
+
+            let body = json!({
+                "id": request_id,
+                "jsonrpc":"2.0",
+                "method":"condenser_api.get_following",
+                "params":[
+                    account.clone(),
+                    start,
+                    "ignore",
+                    IGNORE_LIST_PAGE_SIZE
+                ]
+            });
+
+            let result = self.request(request_id, body).await?;
+
+            let page = result["result"].as_array().ok_or_else(|| Error::RpcResponse(format!("Invalid ignore list for {}", account)))?;
+
+            // Every page after the first starts with the last entry of the previous one
+            let mut last: Option<String> = None;
+            for entry in page.iter() {
+                let following = entry["following"].as_str().ok_or_else(|| Error::RpcResponse(format!("Invalid ignore list entry {}", entry)))?.to_string();
+                ignore_list.insert(following.clone());
+                last = Some(following);
+            }
+
+            match last {
+                Some(last) if page.len() as i64 == IGNORE_LIST_PAGE_SIZE && last != start => start = last,
+                _ => break
+            }
         }
+
+        Ok(ignore_list)
     }
 
-    pub async fn get_ignore_list(&mut self, account: String) -> Vec<String> {
+    pub async fn get_head_block(&mut self) -> Result<i64> {
         let request_id = self.request_id_generator.next();
 
         let body = json!({
             "id": request_id,
             "jsonrpc":"2.0",
-            "method":"condenser_api.get_following",
-            "params":[
-                account,
-                "",
-                "ignore",
-                250
-            ]
+            "method":"condenser_api.get_dynamic_global_properties",
+            "params":[]
         });
 
-        let request_body = serde_json::to_string(&body).unwrap().as_bytes().to_vec();
+        let result = self.request(request_id, body).await?;
 
-        let result = self.request(request_body).await;
+        result["result"]["head_block_number"].as_i64().ok_or_else(|| Error::RpcResponse("Missing head_block_number".to_string()))
+    }
 
-        if result["id"] != request_id {
-            panic!("Request ID does not match! Expected {} got {}", request_id, result["id"]);
-        }
+    pub async fn get_last_irreversible_block(&mut self) -> Result<i64> {
+        let request_id = self.request_id_generator.next();
 
-        let mut ignore_list: Vec<String> = vec![];
-        let result = result["result"].as_array().unwrap().iter();
+        let body = json!({
+            "id": request_id,
+            "jsonrpc":"2.0",
+            "method":"condenser_api.get_dynamic_global_properties",
+            "params":[]
+        });
 
-        for account in result {
-            ignore_list.push(account["following"].as_str().unwrap().to_string());
-        }
+        let result = self.request(request_id, body).await?;
 
-        ignore_list
+        result["result"]["last_irreversible_block_num"].as_i64().ok_or_else(|| Error::RpcResponse("Missing last_irreversible_block_num".to_string()))
     }
 
-    pub async fn get_head_block(&mut self) -> i64 {
+    /// Returns the creation time of a comment, None if it does not exist (anymore)
+    pub async fn get_comment_created(&mut self, author: String, permlink: String) -> Result<Option<NaiveDateTime>> {
         let request_id = self.request_id_generator.next();
 
         let body = json!({
             "id": request_id,
-            "jsonrpc":"2.0",
-            "method":"condenser_api.get_dynamic_global_properties",
-            "params":[]
+            "jsonrpc": "2.0",
+            "method": "condenser_api.get_content",
+            "params": [&author, &permlink]
         });
 
-        let request_body = serde_json::to_string(&body).unwrap().as_bytes().to_vec();
+        let result = self.request(request_id, body).await?;
 
-        let result = self.request(request_body).await;
+        if result["result"]["author"].as_str().unwrap_or_default().is_empty() {
+            return Ok(None);
+        }
 
-        if result["id"] != request_id {
-            panic!("Request ID does not match! Expected {} got {}", request_id, result["id"]);
+        let created = result["result"]["created"].as_str().unwrap_or_default();
+
+        NaiveDateTime::parse_from_str(created, block::TIME_FORMAT)
+            .map(Some)
+            .map_err(|e| Error::RpcResponse(format!("Invalid creation time {:?} of @{}/{}: {}", created, author, permlink, e)))
+    }
+
+    /// Fetches up to `count` blocks starting at `start` in one round trip.
+    /// Uses `block_api.get_block_range` and falls back to a JSON-RPC batch of
+    /// `condenser_api.get_block` calls when the nodes do not support it.
+    pub async fn get_blocks(&mut self, start: i64, count: i64) -> Result<Vec<(i64, SignedBlock)>> {
+        let mut blocks: Option<Vec<(i64, SignedBlock)>> = None;
+
+        if self.use_block_range {
+            match self.get_block_range(start, count).await {
+                Ok(range) => blocks = Some(range),
+                Err(Error::RpcResponse(e)) if method_not_found(&e) => {
+                    warn!("block_api.get_block_range is not available, falling back to batch requests: {}", e);
+                    self.use_block_range = false;
+                }
+                Err(e) => return Err(e)
+            }
         }
 
-        result["result"]["head_block_number"].as_i64().unwrap()
+        let blocks = match blocks {
+            Some(blocks) => blocks,
+            None => self.get_block_batch(start, count).await?
+        };
 
+        if blocks.is_empty() {
+            return Err(Error::RpcResponse(format!("Block {} is not available", start)));
+        }
+
+        Ok(blocks)
     }
 
-    pub async fn get_block(&mut self, block: i64) -> Value {
+    async fn get_block_range(&mut self, start: i64, count: i64) -> Result<Vec<(i64, SignedBlock)>> {
         let request_id = self.request_id_generator.next();
 
         let body = json!({
             "id": request_id,
             "jsonrpc": "2.0",
-            "method": "condenser_api.get_block",
-            "params": [block]
+            "method": "block_api.get_block_range",
+            "params": {
+                "starting_block_num": start,
+                "count": count
+            }
         });
 
-        let request_body = serde_json::to_string(&body).unwrap().as_bytes().to_vec();
+        let result = self.request(request_id, body).await?;
 
-        let result = self.request(request_body).await;
+        let blocks: Vec<SignedBlock> = serde_json::from_value(result["result"]["blocks"].clone())
+            .map_err(|e| Error::RpcResponse(format!("Invalid block range {}+{}: {}", start, count, e)))?;
 
-        if result["id"] != request_id {
-            panic!("Request ID does not match! Expected {} got {}", request_id, result["id"]);
+        Ok(blocks.into_iter().enumerate().map(|(i, mut block)| {
+            block.assign_transaction_ids();
+            (start + i as i64, block)
+        }).collect())
+    }
+
+    async fn get_block_batch(&mut self, start: i64, count: i64) -> Result<Vec<(i64, SignedBlock)>> {
+        let mut requests: Vec<(i64, i64)> = vec![];
+        let mut body: Vec<Value> = vec![];
+
+        for block in start..start + count {
+            let request_id = self.request_id_generator.next();
+            requests.push((request_id, block));
+            body.push(json!({
+                "id": request_id,
+                "jsonrpc": "2.0",
+                "method": "condenser_api.get_block",
+                "params": [block]
+            }));
+        }
+
+        let result = self.rpc.request(Value::Array(body).to_string().into_bytes()).await?;
+
+        let responses = result.as_array().ok_or_else(|| Error::RpcResponse(format!("Invalid batch response for blocks {}+{}", start, count)))?;
+
+        let mut blocks: Vec<(i64, SignedBlock)> = vec![];
+
+        // Batch responses may arrive in any order
+        for (request_id, block) in requests {
+            let response = responses.iter().find(|r| r["id"] == request_id)
+                .ok_or_else(|| Error::IdMismatch { expected: request_id, got: Value::Null })?;
+
+            if !response["error"].is_null() {
+                return Err(Error::RpcResponse(response["error"].to_string()));
+            }
+
+            if response["result"].is_null() {
+                break;
+            }
+
+            let signed_block = serde_json::from_value::<SignedBlock>(response["result"].clone())
+                .map_err(|e| Error::RpcResponse(format!("Invalid block {}: {}", block, e)))?;
+
+            blocks.push((block, signed_block));
         }
 
-        result
+        Ok(blocks)
     }
 }
 
+/// A row of the Hive Engine `tokens.balances` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TokenBalance {
+    pub account: String,
+    pub symbol: String,
+    /// Liquid token
+    pub balance: TokenAmount,
+    /// Staked token, without delegations
+    pub stake: TokenAmount,
+    pub pendingUnstake: TokenAmount,
+    pub delegationsIn: TokenAmount,
+    pub delegationsOut: TokenAmount,
+}
+
 pub struct HiveEngine {
-    rpc_host: String,
-    http_client: Client,
+    rpc: RpcClient,
     request_id_generator: Counter,
 }
 
 impl HiveEngine {
-    async fn request(&self, body: Vec<u8>) -> Value {
-        let result = &self.http_client
-            .post(&self.rpc_host)
-            .header("Content-Type", "application/json")
-            .body(reqwest::Body::from(body)).send()
-            .await.unwrap().json::<Value>().await;
-
-        match result {
-            Ok(r) => r.to_owned(),
-            Err(_) => panic!("Failed to request Hive Engine RPC!")
+    async fn request(&mut self, request_id: i64, body: Value) -> Result<Value> {
+        let request_body = body.to_string().into_bytes();
+
+        let result = self.rpc.request(request_body).await?;
+
+        if result["id"] != request_id {
+            return Err(Error::IdMismatch { expected: request_id, got: result["id"].clone() });
         }
+
+        Ok(result)
     }
 
-    pub fn new(rpc_host: String, http_client: Client, request_id_generator: Counter) -> HiveEngine {
-        HiveEngine {
-            rpc_host,
-            http_client,
+    pub fn new(rpc_hosts: Vec<String>, http_client: Client, request_id_generator: Counter, rpc_options: RpcOptions) -> Result<HiveEngine> {
+        Ok(HiveEngine {
+            rpc: RpcClient::new("Hive Engine", rpc_hosts, http_client, rpc_options)?,
             request_id_generator,
-        }
+        })
     }
 
-    pub async fn balance(&mut self, account: String, token: String) -> f64 {
+    /// Balance of the token with exactly this symbol. Accounts without a balance row have nothing.
+    pub async fn token_balance(&mut self, account: String, token: String) -> Result<TokenBalance> {
         let request_id = self.request_id_generator.next();
 
         let body = json!({
@@ -192,54 +363,62 @@ impl HiveEngine {
             "params": {
                 "contract": "tokens",
                 "query": {
-                    "account": account,
-                    "symbol": token
+                    "account": account.clone(),
+                    "symbol": token.clone()
                 },
                 "table": "balances"
             }
         });
 
-        let request_body = serde_json::to_string(&body).unwrap().as_bytes().to_vec();
-
-        let mut result = self.request(request_body).await;
+        let mut result = self.request(request_id, body).await?;
 
-        if result["id"] != request_id {
-            panic!("Request ID does not match! Expected {} got {}", request_id, result["id"]);
-        }
+        let balances = result["result"].as_array_mut().ok_or_else(|| Error::RpcResponse(format!("Invalid balances for {}", account)))?;
 
-        match result["result"].as_array_mut().unwrap().iter().find(|b| b.to_owned()["symbol"].as_str().unwrap().to_string().contains("BEER")) {
-            Some(b) => b["balance"].as_str().unwrap().parse::<f64>().unwrap(),
-            None => 0f64
+        match balances.iter().find(|b| b["symbol"].as_str() == Some(token.as_str())) {
+            Some(b) => serde_json::from_value::<TokenBalance>(b.clone()).map_err(|e| Error::RpcResponse(format!("Invalid balance for {}: {}", account, e))),
+            None => Ok(TokenBalance {
+                account,
+                symbol: token,
+                ..TokenBalance::default()
+            })
         }
     }
-    pub async fn stake(&mut self, account: String, token: String) -> f64 {
+
+    /// Number of decimal places of the token
+    pub async fn token_precision(&mut self, token: String) -> Result<u32> {
         let request_id = self.request_id_generator.next();
 
         let body = json!({
             "id": request_id,
             "jsonrpc": "2.0",
-            "method": "find",
+            "method": "findOne",
             "params": {
                 "contract": "tokens",
                 "query": {
-                    "account": account,
-                    "symbol": token
+                    "symbol": token.clone()
                 },
-                "table": "balances"
+                "table": "tokens"
             }
         });
 
-        let request_body = serde_json::to_string(&body).unwrap().as_bytes().to_vec();
-
-        let mut result = self.request(request_body).await;
+        let result = self.request(request_id, body).await?;
 
-        if result["id"] != request_id {
-            panic!("Request ID does not match! Expected {} got {}", request_id, result["id"]);
+        match result["result"]["precision"].as_u64() {
+            Some(precision) => Ok(precision as u32),
+            None => Err(Error::RpcResponse(format!("Unknown token {}", token)))
         }
+    }
+}
 
-        match result["result"].as_array_mut().unwrap().iter().find(|b| b.to_owned()["symbol"].as_str().unwrap().to_string().contains("BEER")) {
-            Some(b) => b["stake"].as_str().unwrap().parse::<f64>().unwrap(),
-            None => 0f64
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_missing_methods_disable_the_block_range() {
+        assert!(method_not_found(r#"{"code":-32601,"message":"Method not found"}"#));
+        assert!(method_not_found(r#"{"code":-32003,"message":"Assert Exception:api_itr != _registered_apis.end(): Could not find API block_api"}"#));
+        assert!(!method_not_found(r#"{"code":-32003,"message":"Unable to acquire database lock"}"#));
+        assert!(!method_not_found("Invalid block range 1+50: missing field `timestamp`"));
     }
 }
@@ -0,0 +1,259 @@
+// The models mirror the chain format, not every field is used by the processor
+#![allow(dead_code)]
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Deserializer};
+use serde::de::{DeserializeOwned, Error as DeError};
+use serde_json::Value;
+use crate::error::Error;
+use crate::hive::hive_ops;
+
+/// Format of all timestamps on the chain, always UTC
+pub const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignedBlock {
+    pub previous: String,
+    pub timestamp: String,
+    pub witness: String,
+    #[serde(default)]
+    pub block_id: String,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    #[serde(default)]
+    pub transaction_ids: Vec<String>,
+}
+
+impl SignedBlock {
+    /// Block time, fails if the timestamp is not in the chain format
+    pub fn time(&self) -> crate::error::Result<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(&self.timestamp, TIME_FORMAT)
+            .map_err(|e| Error::MalformedOperation(format!("Invalid block timestamp {:?}: {}", self.timestamp, e)))
+    }
+
+    /// `block_api` blocks list the transaction ids separately instead of inside each transaction
+    pub fn assign_transaction_ids(&mut self) {
+        for (tx, tx_id) in self.transactions.iter_mut().zip(self.transaction_ids.iter()) {
+            if tx.transaction_id.is_empty() {
+                tx.transaction_id = tx_id.clone();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Transaction {
+    #[serde(default)]
+    pub transaction_id: String,
+    #[serde(default)]
+    pub block_num: i64,
+    pub operations: Vec<Operation>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommentOperation {
+    pub parent_author: String,
+    pub parent_permlink: String,
+    pub author: String,
+    pub permlink: String,
+    pub title: String,
+    pub body: String,
+    pub json_metadata: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommentOptionsOperation {
+    pub author: String,
+    pub permlink: String,
+    pub max_accepted_payout: Value,
+    pub percent_hbd: i64,
+    pub allow_votes: bool,
+    pub allow_curation_rewards: bool,
+    #[serde(default)]
+    pub extensions: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomJsonOperation {
+    pub required_auths: Vec<String>,
+    pub required_posting_auths: Vec<String>,
+    pub id: String,
+    pub json: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeleteCommentOperation {
+    pub author: String,
+    pub permlink: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransferOperation {
+    pub from: String,
+    pub to: String,
+    pub amount: Value,
+    pub memo: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VoteOperation {
+    pub voter: String,
+    pub author: String,
+    pub permlink: String,
+    pub weight: i64,
+}
+
+/// A Hive operation in the condenser `[name, payload]` or the `block_api`
+/// `{"type": "name_operation", "value": payload}` format.
+/// Operations without a handler are kept as `Other`, known operations with an unexpected
+/// payload as `Malformed` so a single bad operation does not fail the whole block.
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Comment(CommentOperation),
+    CommentOptions(CommentOptionsOperation),
+    CustomJson(CustomJsonOperation),
+    DeleteComment(DeleteCommentOperation),
+    Transfer(TransferOperation),
+    Vote(VoteOperation),
+    Other(String),
+    Malformed(String, String), // Operation name and deserialize error
+}
+
+impl Operation {
+    fn parse<T: DeserializeOwned>(name: &str, payload: Value, variant: fn(T) -> Operation) -> Operation {
+        match serde_json::from_value::<T>(payload) {
+            Ok(op) => variant(op),
+            Err(e) => Operation::Malformed(name.to_string(), e.to_string())
+        }
+    }
+
+    pub fn from(name: &str, payload: Value) -> Operation {
+        match name {
+            hive_ops::COMMENT => Operation::parse(name, payload, Operation::Comment),
+            hive_ops::COMMENT_OPTIONS => Operation::parse(name, payload, Operation::CommentOptions),
+            hive_ops::CUSTOM_JSON => Operation::parse(name, payload, Operation::CustomJson),
+            hive_ops::DELETE_COMMENT => Operation::parse(name, payload, Operation::DeleteComment),
+            hive_ops::TRANSFER => Operation::parse(name, payload, Operation::Transfer),
+            hive_ops::VOTE => Operation::parse(name, payload, Operation::Vote),
+            _ => Operation::Other(name.to_string())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Operation {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let op: Value = Deserialize::deserialize(deserializer)?;
+
+        match op {
+            Value::Array(mut op) if op.len() == 2 => {
+                let payload = op.pop().unwrap_or_default();
+                match op.pop() {
+                    Some(Value::String(name)) => Ok(Operation::from(&name, payload)),
+                    _ => Err(D::Error::custom("invalid operation name"))
+                }
+            }
+            Value::Object(mut op) => {
+                let payload = op.remove("value").unwrap_or_default();
+                match op.remove("type") {
+                    Some(Value::String(name)) => Ok(Operation::from(name.trim_end_matches("_operation"), payload)),
+                    _ => Err(D::Error::custom("invalid operation type"))
+                }
+            }
+            _ => Err(D::Error::custom(format!("invalid operation: {}", op)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn comment() -> Value {
+        json!({
+            "parent_author": "bob",
+            "parent_permlink": "post",
+            "author": "alice",
+            "permlink": "re-post",
+            "title": "",
+            "body": "!BEER",
+            "json_metadata": "{}"
+        })
+    }
+
+    fn assert_comment(op: &Operation) {
+        match op {
+            Operation::Comment(comment) => {
+                assert_eq!(comment.author, "alice");
+                assert_eq!(comment.parent_author, "bob");
+                assert_eq!(comment.body, "!BEER");
+            }
+            op => panic!("expected a comment, got {:?}", op)
+        }
+    }
+
+    #[test]
+    fn parses_comments_in_both_formats() {
+        assert_comment(&serde_json::from_value::<Operation>(json!(["comment", comment()])).unwrap());
+        assert_comment(&serde_json::from_value::<Operation>(json!({ "type": "comment_operation", "value": comment() })).unwrap());
+    }
+
+    #[test]
+    fn keeps_unknown_operations() {
+        let op = serde_json::from_value::<Operation>(json!(["claim_account", { "creator": "alice" }])).unwrap();
+        assert!(matches!(op, Operation::Other(name) if name == "claim_account"));
+
+        let op = serde_json::from_value::<Operation>(json!({ "type": "claim_account_operation", "value": {} })).unwrap();
+        assert!(matches!(op, Operation::Other(name) if name == "claim_account"));
+    }
+
+    #[test]
+    fn rejects_invalid_operations() {
+        assert!(serde_json::from_value::<Operation>(json!(["comment"])).is_err());
+        assert!(serde_json::from_value::<Operation>(json!([1, comment()])).is_err());
+        assert!(serde_json::from_value::<Operation>(json!({ "value": comment() })).is_err());
+        assert!(serde_json::from_value::<Operation>(json!("comment")).is_err());
+    }
+
+    #[test]
+    fn malformed_operations_keep_the_block() {
+        let mut incomplete = comment();
+        incomplete.as_object_mut().unwrap().remove("author");
+
+        let block: SignedBlock = serde_json::from_value(json!({
+            "previous": "0000000000000000000000000000000000000000",
+            "timestamp": "2024-01-01T00:00:03",
+            "witness": "witness",
+            "transactions": [{
+                "transaction_id": "aaaa",
+                "block_num": 1,
+                "operations": [["comment", incomplete], ["comment", comment()]]
+            }]
+        })).unwrap();
+
+        let operations = &block.transactions[0].operations;
+        assert!(matches!(&operations[0], Operation::Malformed(name, e) if name == "comment" && e.contains("author")));
+        assert_comment(&operations[1]);
+    }
+
+    #[test]
+    fn assigns_block_api_transaction_ids() {
+        let mut block: SignedBlock = serde_json::from_value(json!({
+            "previous": "0000000000000000000000000000000000000000",
+            "timestamp": "2024-01-01T00:00:03",
+            "witness": "witness",
+            "block_id": "0000000100000000000000000000000000000000",
+            "transactions": [
+                { "operations": [{ "type": "comment_operation", "value": comment() }] },
+                { "transaction_id": "kept", "operations": [] }
+            ],
+            "transaction_ids": ["aaaa", "bbbb"]
+        })).unwrap();
+
+        block.assign_transaction_ids();
+
+        assert_eq!(block.transactions[0].transaction_id, "aaaa");
+        assert_eq!(block.transactions[1].transaction_id, "kept");
+        assert_comment(&block.transactions[0].operations[0]);
+        assert_eq!(block.time().unwrap().to_string(), "2024-01-01 00:00:03");
+    }
+}
@@ -0,0 +1,100 @@
+use std::cmp::min;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use crate::error::Result;
+use crate::hive::{Hive, SignedBlock};
+
+pub const BLOCK_INTERVAL: Duration = Duration::from_secs(3);
+
+pub type FetchedBlock = Result<(i64, SignedBlock)>;
+
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// Keep polling for new blocks after reaching the target
+    pub follow: bool,
+    /// Use the last irreversible block instead of the head block as target
+    pub irreversible: bool,
+    /// Number of blocks to stay behind the target
+    pub block_lag: i64,
+    /// Number of blocks fetched per request
+    pub batch_size: i64,
+    /// Number of batches fetched ahead of the processing
+    pub prefetch: usize,
+}
+
+pub async fn sync_target(hive: &mut Hive, options: &SyncOptions) -> Result<i64> {
+    let height = if options.irreversible {
+        hive.get_last_irreversible_block().await?
+    } else {
+        hive.get_head_block().await?
+    };
+
+    Ok(height - options.block_lag)
+}
+
+/// Fetches blocks in batches on a separate task and hands them to the processor in order.
+/// At most `prefetch` batches are buffered, so fetching pauses while the processor catches up.
+pub struct BlockFetcher {
+    hive: Hive,
+    options: SyncOptions,
+}
+
+impl BlockFetcher {
+    pub fn new(hive: Hive, options: SyncOptions) -> BlockFetcher {
+        BlockFetcher {
+            hive,
+            options,
+        }
+    }
+
+    /// Starts fetching at `start`. The channel is closed once `target` is reached, unless following the chain.
+    pub fn spawn(self, start: i64, target: i64) -> Receiver<FetchedBlock> {
+        let capacity = (self.options.batch_size.max(1) as usize) * self.options.prefetch.max(1);
+        let (sender, receiver) = channel::<FetchedBlock>(capacity);
+
+        tokio::spawn(self.run(start, target, sender));
+
+        receiver
+    }
+
+    async fn run(mut self, start: i64, target: i64, sender: Sender<FetchedBlock>) {
+        let mut next = start;
+        let mut target = target;
+
+        loop {
+            if next > target {
+                if !self.options.follow {
+                    return;
+                }
+
+                tokio::time::sleep(BLOCK_INTERVAL).await;
+                match sync_target(&mut self.hive, &self.options).await {
+                    Ok(height) => target = height,
+                    Err(e) => warn!("Failed to fetch head block: {}", e)
+                }
+                continue;
+            }
+
+            let count = min(self.options.batch_size.max(1), target - next + 1);
+
+            match self.hive.get_blocks(next, count).await {
+                Ok(blocks) => {
+                    for (block_num, block) in blocks {
+                        if sender.send(Ok((block_num, block))).await.is_err() {
+                            return;
+                        }
+                        next = block_num + 1;
+                    }
+                }
+                Err(e) if self.options.follow && e.is_retryable() => {
+                    warn!("Retrying blocks {}+{}: {}", next, count, e);
+                    tokio::time::sleep(BLOCK_INTERVAL).await;
+                }
+                Err(e) => {
+                    sender.send(Err(e)).await.ok();
+                    return;
+                }
+            }
+        }
+    }
+}
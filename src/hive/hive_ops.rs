@@ -1,3 +1,6 @@
 pub const COMMENT: &str = "comment";
-
-
+pub const COMMENT_OPTIONS: &str = "comment_options";
+pub const CUSTOM_JSON: &str = "custom_json";
+pub const DELETE_COMMENT: &str = "delete_comment";
+pub const TRANSFER: &str = "transfer";
+pub const VOTE: &str = "vote";
@@ -0,0 +1,157 @@
+use std::cmp::min;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use reqwest::{Client, StatusCode};
+use crate::error::{Error, Result};
+
+const BACKOFF_BASE: Duration = Duration::from_millis(250);
+const BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct RpcOptions {
+    /// Number of attempts before a request is given up
+    pub retries: u32,
+    /// Timeout for a single attempt
+    pub timeout: Duration,
+    /// Time a failing node is skipped before it is tried again
+    pub cooldown: Duration,
+}
+
+struct RpcNode {
+    host: String,
+    failures: u32,
+    cooldown_until: Option<Instant>,
+}
+
+impl RpcNode {
+    fn available(&self, now: Instant) -> bool {
+        match self.cooldown_until {
+            Some(until) => until <= now,
+            None => true
+        }
+    }
+}
+
+/// JSON-RPC client that rotates through a list of nodes.
+/// A node that times out, answers with a 5xx status, invalid JSON or a JSON-RPC error
+/// is put on cooldown and the request is retried on the next node with exponential backoff.
+pub struct RpcClient {
+    name: &'static str,
+    nodes: Vec<RpcNode>,
+    current: usize,
+    http_client: Client,
+    options: RpcOptions,
+}
+
+impl RpcClient {
+    pub fn new(name: &'static str, hosts: Vec<String>, http_client: Client, options: RpcOptions) -> Result<RpcClient> {
+        let nodes: Vec<RpcNode> = hosts.into_iter()
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .map(|host| RpcNode {
+                host,
+                failures: 0,
+                cooldown_until: None,
+            })
+            .collect();
+
+        if nodes.is_empty() {
+            return Err(Error::Config(format!("No {} nodes configured!", name)));
+        }
+
+        Ok(RpcClient {
+            name,
+            nodes,
+            current: 0,
+            http_client,
+            options,
+        })
+    }
+
+    /// Picks the current node if it is healthy, otherwise the next healthy one.
+    /// When every node is on cooldown the one that recovers first is used.
+    fn select_node(&mut self) -> usize {
+        let now = Instant::now();
+
+        for offset in 0..self.nodes.len() {
+            let index = (self.current + offset) % self.nodes.len();
+            if self.nodes[index].available(now) {
+                self.current = index;
+                return index;
+            }
+        }
+
+        let index = (0..self.nodes.len())
+            .min_by_key(|i| self.nodes[*i].cooldown_until)
+            .unwrap_or(0);
+        self.current = index;
+        index
+    }
+
+    fn mark_failed(&mut self, index: usize, reason: &Error) {
+        let node = &mut self.nodes[index];
+        node.failures += 1;
+        node.cooldown_until = Some(Instant::now() + self.options.cooldown);
+
+        warn!("{} node {} failed ({} times in a row): {}", self.name, node.host, node.failures, reason);
+
+        self.current = (index + 1) % self.nodes.len();
+    }
+
+    fn mark_healthy(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        node.failures = 0;
+        node.cooldown_until = None;
+    }
+
+    async fn attempt(&self, index: usize, body: Vec<u8>) -> Result<Value> {
+        let response = self.http_client
+            .post(&self.nodes[index].host)
+            .header("Content-Type", "application/json")
+            .timeout(self.options.timeout)
+            .body(reqwest::Body::from(body)).send()
+            .await
+            .map_err(|e| Error::RpcTransport(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Error::RpcTransport(format!("HTTP status {}", status)));
+        }
+
+        let result = response.json::<Value>().await.map_err(|e| Error::RpcTransport(e.to_string()))?;
+
+        if !result["error"].is_null() {
+            return Err(Error::RpcResponse(result["error"].to_string()));
+        }
+
+        Ok(result)
+    }
+
+    pub async fn request(&mut self, body: Vec<u8>) -> Result<Value> {
+        let mut backoff = BACKOFF_BASE;
+        let attempts = self.options.retries.max(1);
+
+        for attempt in 1..=attempts {
+            let index = self.select_node();
+
+            match self.attempt(index, body.clone()).await {
+                Ok(result) => {
+                    self.mark_healthy(index);
+                    return Ok(result);
+                }
+                Err(e) => {
+                    self.mark_failed(index, &e);
+
+                    if attempt == attempts {
+                        return Err(e);
+                    }
+
+                    tokio::time::sleep(backoff).await;
+                    backoff = min(backoff * 2, BACKOFF_MAX);
+                }
+            }
+        }
+
+        Err(Error::RpcTransport(format!("Failed to request {} RPC!", self.name)))
+    }
+}
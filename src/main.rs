@@ -4,18 +4,37 @@
 #[macro_use]
 extern crate log;
 extern crate pretty_env_logger;
-#[macro_use]
-extern crate serde_json;
 
+use std::collections::HashSet;
 use std::fmt::Debug;
-use clap::Parser;
-use crate::beerlover::Beerlover;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tokio::signal::unix::{signal, SignalKind};
+use crate::amount::TokenAmount;
+use crate::beerlover::{BannedWords, Beerlover, Command, ShareTier};
+use crate::broadcast::{Broadcast, BroadcastApi};
+use crate::config::Config;
+use crate::error::{Error, Result};
 use crate::hive::{Counter, Hive, HiveEngine, HivePost, HivePostList};
-use crate::mongo::{Database as BeerDatabase, DatabaseOptions, StakingQueueAction, StakingQueueEntry};
+use crate::hive::fetcher::{sync_target, BlockFetcher, SyncOptions, BLOCK_INTERVAL};
+use crate::hive::rpc::RpcOptions;
+use crate::mongo::{BeerTransfer, Database as BeerDatabase, DatabaseOptions, StakingQueueAction, StakingQueueEntry};
+use crate::rules::{RuleConfig, RuleContext, RuleEngine};
+use crate::state::{FileStateStore, MongoStateStore, StateStore};
 
 mod hive;
+mod amount;
 mod beerlover;
+mod broadcast;
+mod cache;
+mod config;
+mod error;
 mod mongo;
+mod rules;
+mod state;
 
 /// Beerlover - Reward !BEER comments on the HIVE blockchain
 #[derive(Parser, Debug)]
@@ -36,11 +55,11 @@ pub struct CLIARGS {
     /// Broadcast API Host
     #[clap(short = 'e', long, value_parser, default_value = "http://127.0.0.1:6666/broacast")]
     broadcast_api_host: String,
-    /// Hive RPC API Host
-    #[clap(short = 'f', long, value_parser, default_value = "https://api.deathwing.me")]
+    /// Hive RPC API Hosts. Comma seperated, the next host is used when one fails
+    #[clap(short = 'f', long, value_parser, default_value = "https://api.deathwing.me,https://api.hive.blog,https://anyx.io")]
     rpc_host: String,
-    /// Hive Engine RPC API Host
-    #[clap(short = 'g', long, value_parser, default_value = "https://ha.herpc.dtools.dev/contracts")]
+    /// Hive Engine RPC API Hosts. Comma seperated, the next host is used when one fails
+    #[clap(short = 'g', long, value_parser, default_value = "https://ha.herpc.dtools.dev/contracts,https://api.hive-engine.com/rpc/contracts")]
     he_rpc_host: String,
     /// Hive Engine Token Symbol
     #[clap(short = 'i', long, value_parser, default_value = "BEER")]
@@ -48,25 +67,146 @@ pub struct CLIARGS {
     /// Hive Account
     #[clap(short = 'j', long, value_parser, default_value = "beerlover")]
     hive_account: String,
-    /// Trigger word - Use this in a HIVE comment to share token
+    /// Trigger word - Use this in a HIVE comment to share token. Ignored when the config file has commands
     #[clap(short = 'k', long, value_parser, default_value = "!BEER")]
     trigger_word: String,
     /// List of accounts to load the ignored user from and use them as blacklist. Comma seperated
     #[clap(short = 'l', long, value_parser, default_value = "beerlover,detlev,louis88,wehmoen")]
     banned_accounts: String,
-    /// Share Ratio. Allow 1 Share per n token in wallet
-    #[clap(short = 'm', long, value_parser, default_value_t = 24.0)]
-    share_ration: f64,
-    /// Reward amount. Number of token to be staked to parent author
+    /// Share Ratio. Allow 1 Share per n token in wallet. Ignored when the config file has share tiers
+    #[clap(short = 'm', long, value_parser, default_value = "24")]
+    share_ration: TokenAmount,
+    /// Reward amount. Number of token to be staked to parent author. Ignored when the config file has commands
     #[clap(short = 'n', long, value_parser, default_value = "0.100")]
-    reward_amount: String,
+    reward_amount: TokenAmount,
     /// Print debug info
     #[clap(short = 'o', long, value_parser, default_value_t = false)]
     debug_info: bool,
     /// Set Block State - use with caution
     #[clap(short = 'p', long, value_parser, default_value_t = 0)]
     set_block_state: i64,
+    /// Keep following the chain after reaching the head block
+    #[clap(short = 'q', long, value_parser, default_value_t = false)]
+    follow: bool,
+    /// Only process blocks that are at least n blocks behind the head (or last irreversible) block
+    #[clap(short = 'r', long, value_parser, default_value_t = 0)]
+    block_lag: i64,
+    /// Only process blocks up to the last irreversible block
+    #[clap(short = 's', long, value_parser, default_value_t = false)]
+    irreversible: bool,
+    /// List of banned words. Comments containing one of them are not rewarded. Comma seperated
+    #[clap(short = 't', long, value_parser, default_value = "!PIZZA,!LUV,!ENGAGE")]
+    banned_words: String,
+    /// Path to a JSON config file
+    #[clap(short = 'u', long, value_parser)]
+    config: Option<String>,
+    /// MongoDB banned words collection name
+    #[clap(short = 'v', long, value_parser, default_value = "bannedwords")]
+    mongodb_banned_words_collection: String,
+    /// Match banned words case-insensitively
+    #[clap(short = 'w', long, value_parser, default_value_t = false)]
+    banned_words_case_insensitive: bool,
+    /// Treat banned words as regular expressions
+    #[clap(short = 'x', long, value_parser, default_value_t = false)]
+    banned_words_regex: bool,
+    /// Reload interval in seconds for the banned words and banned accounts while following the chain
+    #[clap(short = 'y', long, value_parser, default_value_t = 300)]
+    reload_interval: u64,
+    /// Number of attempts for a RPC request before giving up
+    #[clap(short = 'z', long, value_parser, default_value_t = 5)]
+    rpc_retries: u32,
+    /// Timeout in seconds for a single RPC request
+    #[clap(short = 'A', long, value_parser, default_value_t = 15)]
+    rpc_timeout: u64,
+    /// Time in seconds a failing RPC node is skipped
+    #[clap(short = 'B', long, value_parser, default_value_t = 60)]
+    rpc_cooldown: u64,
+    /// Number of blocks fetched per request
+    #[clap(short = 'C', long, value_parser, default_value_t = 50)]
+    batch_size: i64,
+    /// Number of block batches fetched ahead of the processing
+    #[clap(short = 'D', long, value_parser, default_value_t = 4)]
+    prefetch: usize,
+    /// Where the last processed block is stored
+    #[clap(short = 'E', long, value_enum, default_value = "mongo")]
+    state_backend: StateBackend,
+    /// State file used by the file backend. Also read once when the MongoDB backend has no checkpoint yet
+    #[clap(short = 'F', long, value_parser, default_value = "./state.dat")]
+    state_file: PathBuf,
+    /// Instance name. Seperates the checkpoints of multiple processors sharing one database
+    #[clap(short = 'G', long, value_parser, default_value = "beerlover")]
+    instance_name: String,
+    /// MongoDB state collection name
+    #[clap(short = 'H', long, value_parser, default_value = "state")]
+    mongodb_state_collection: String,
+    /// Number of processed operations kept in memory to skip the duplicate check in MongoDB. 0 disables this cache, see --seen-filter for new operations
+    #[clap(short = 'I', long, value_parser, default_value_t = 10000)]
+    processed_cache_size: usize,
+    /// How to handle comments that get the trigger word by editing them. Only used without a rules list in the config file
+    #[clap(short = 'J', long, value_enum, default_value = "ignore")]
+    edit_policy: EditPolicy,
+    /// MongoDB collection of accounts that do not want to receive token
+    #[clap(short = 'K', long, value_parser, default_value = "optouts")]
+    mongodb_optout_collection: String,
+    /// MongoDB collection of accounts allowed to give token in allow-list mode
+    #[clap(short = 'L', long, value_parser, default_value = "allowlist")]
+    mongodb_allowlist_collection: String,
+    /// Only reward comments of accounts on the allow-list. Only used without a rules list in the config file
+    #[clap(short = 'M', long, value_parser, default_value_t = false)]
+    allow_list: bool,
+    /// Which stake counts toward the share allowance of a user
+    #[clap(short = 'N', long, value_enum, default_value = "own")]
+    stake_mode: StakeMode,
+    /// Keep a bloom filter of all stored operations in memory, so new comments skip the duplicate check in MongoDB.
+    /// Reads the whole queue and transfers collections on start. Only used with --follow and while no other instance writes to the database
+    #[clap(short = 'O', long, value_parser, default_value_t = false)]
+    seen_filter: bool,
+    #[clap(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum StateBackend {
+    Mongo,
+    File,
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq, Eq)]
+pub enum StakeMode {
+    /// Only token staked by the user
+    Own,
+    /// Own stake plus token the user delegated to other accounts
+    DelegatedOut,
+    /// Own stake plus token delegated to the user
+    DelegatedIn,
+    /// Own stake plus delegations in both directions
+    All,
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq, Eq)]
+pub enum EditPolicy {
+    /// Comments that get the trigger word through an edit are not rewarded
+    Ignore,
+    /// Comments that get the trigger word through an edit are rewarded once
+    Reward,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Mode {
+    /// Process Hive blocks and add !BEER comments to the queue (default)
+    Process,
+    /// Stake the pending rewards from the queue and reply to the !BEER comments
+    Broadcast(BroadcastArgs),
+}
 
+#[derive(Args, Debug)]
+pub struct BroadcastArgs {
+    /// Hive Engine chain id used for the custom_json operation
+    #[clap(long, value_parser, default_value = broadcast::HIVE_ENGINE_CHAIN_ID)]
+    he_chain_id: String,
+    /// Reply comment. {from}, {to}, {amount} and {symbol} are replaced with the reward details
+    #[clap(long, value_parser, default_value = "Hey @{to}, here is a little bit of `{amount} {symbol}` from @{from} for you. Enjoy it!")]
+    reply_template: String,
 }
 
 
@@ -76,53 +216,361 @@ async fn main() {
 
     let args: CLIARGS = CLIARGS::parse();
 
-    let counter = Counter::new(0);
-    let he_counter = Counter::new(0);
     let client = reqwest::Client::new();
 
-    let mut hive: Hive = Hive::new(args.rpc_host.clone(), client.clone(), counter);
-    let mut hive_engine: HiveEngine = HiveEngine::new(args.he_rpc_host.clone(), client.clone(), he_counter);
-
     let db_options: DatabaseOptions = DatabaseOptions {
         uri: args.mongodb_uri.clone(),
         db_name: args.mongodb_name.clone(),
         collection_name: args.mongodb_collection.clone(),
         queue_collection_name: args.mongodb_queue_collection.clone(),
+        banned_words_collection_name: args.mongodb_banned_words_collection.clone(),
+        state_collection_name: args.mongodb_state_collection.clone(),
+        optout_collection_name: args.mongodb_optout_collection.clone(),
+        allowlist_collection_name: args.mongodb_allowlist_collection.clone(),
+        processed_cache_size: args.processed_cache_size,
     };
 
-    let database: BeerDatabase = BeerDatabase::new(db_options).await;
+    let result = match BeerDatabase::new(db_options).await {
+        Ok(database) => match &args.mode {
+            Some(Mode::Broadcast(broadcast_args)) => broadcast(&args, broadcast_args, client, database).await,
+            Some(Mode::Process) | None => process(&args, client, database).await
+        },
+        Err(e) => Err(e)
+    };
 
-    let banned_accounts: Vec<String> = args.banned_accounts.as_str().split(",").map(str::to_string).collect();
-    let mut banned_account_names: Vec<String> = vec![];
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Hive accepts one comment per account every 3 seconds
+const REPLY_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Replies to the comment of a transfer and records the reply.
+/// The permlink is derived from the stake transaction, so a retried reply edits a reply that went through after all.
+async fn reply(args: &CLIARGS, broadcast_args: &BroadcastArgs, broadcast_api: &BroadcastApi, database: &BeerDatabase, transfer: &BeerTransfer, last_reply: &mut Option<Instant>) -> Result<()> {
+    if let Some(last_reply) = last_reply {
+        tokio::time::sleep(REPLY_INTERVAL.saturating_sub(last_reply.elapsed())).await;
+    }
+
+    let body = broadcast_args.reply_template
+        .replace("{from}", &transfer.from)
+        .replace("{to}", &transfer.to)
+        .replace("{amount}", &transfer.amount.unwrap_or(args.reward_amount).to_string())
+        .replace("{symbol}", &args.he_token_symbol);
+    let permlink: String = format!("re-{}-{}", transfer.permlinkFrom, transfer.txIdTo).to_lowercase().chars().take(255).collect();
+
+    let result = broadcast_api.comment(args.hive_account.clone(), transfer.from.clone(), transfer.permlinkFrom.clone(), permlink, body).await;
+    *last_reply = Some(Instant::now());
+
+    match result {
+        Some(tx_id) => {
+            info!("Replied: [{}] @{}/{}", tx_id, transfer.from, transfer.permlinkFrom);
+            database.set_replied(transfer, tx_id).await?;
+        }
+        None => warn!("Failed to reply to @{}/{}, retrying on the next run", transfer.from, transfer.permlinkFrom)
+    }
+
+    Ok(())
+}
+
+async fn broadcast(args: &CLIARGS, broadcast_args: &BroadcastArgs, client: reqwest::Client, database: BeerDatabase) -> Result<()> {
+    let broadcast_api: BroadcastApi = BroadcastApi::new(args.broadcast_api_host.clone(), client);
+    let mut last_reply: Option<Instant> = None;
+
+    // Replies that failed in an earlier run
+    for transfer in database.pending_replies().await? {
+        reply(args, broadcast_args, &broadcast_api, &database, &transfer, &mut last_reply).await?;
+    }
+
+    // Entries left in broadcasting by an earlier run are never sent again automatically
+    for entry in database.queue_entries(StakingQueueAction::Broadcasting).await? {
+        if database.already_transferred(entry.from_tx.clone(), entry.from_op_index).await? {
+            database.remove_from_queue(&entry).await?;
+        } else {
+            warn!("Stake may have been broadcast, check Hive Engine and set the action back to stakeandcomment if it was not: {}", entry);
+        }
+    }
+
+    let entries = database.queue_entries(StakingQueueAction::StakeAndComment).await?;
+
+    info!("Queue has {} pending rewards!", entries.len());
+
+    for entry in entries {
+        if database.already_transferred(entry.from_tx.clone(), entry.from_op_index).await? {
+            warn!("Removing already transferred entry from queue: {}", entry);
+            database.remove_from_queue(&entry).await?;
+            continue;
+        }
+
+        // Marked before sending so a crash or an unknown outcome never leads to a second stake
+        if !database.mark_broadcasting(&entry).await? {
+            warn!("Entry is no longer queued, skipping: {}", entry);
+            continue;
+        }
+
+        let stake_tx = match broadcast_api.stake(args.hive_account.clone(), broadcast_args.he_chain_id.clone(), entry.to.clone(), entry.symbol.clone(), entry.amount.to_string()).await {
+            Broadcast::Sent(tx_id) => tx_id,
+            Broadcast::NotSent(e) => {
+                warn!("{}, keeping entry in queue: {}", e, entry);
+                database.unmark_broadcasting(&entry).await?;
+                continue;
+            }
+            Broadcast::Unknown(e) => {
+                warn!("{}, stake may have been broadcast, keeping entry in broadcasting: {}", e, entry);
+                continue;
+            }
+        };
+
+        // The entry leaves the queue only after the transfer is recorded
+        let transfer = BeerTransfer::from(&entry, stake_tx.clone());
+        database.add_transfer(transfer.clone()).await?;
+        database.remove_from_queue(&entry).await?;
+
+        info!("Staked: [{}] {}", stake_tx, entry);
+
+        reply(args, broadcast_args, &broadcast_api, &database, &transfer, &mut last_reply).await?;
+    }
+
+    Ok(())
+}
+
+async fn load_banned_accounts(args: &CLIARGS, hive: &mut Hive) -> Result<HashSet<String>> {
+    let banned_accounts: Vec<String> = args.banned_accounts.as_str().split(',').map(str::to_string).collect();
+    let mut banned_account_names: HashSet<String> = HashSet::new();
 
     for account in banned_accounts {
-        let account_names: Vec<String> = hive.get_ignore_list(account).await;
-        banned_account_names = [&banned_account_names[..], &account_names[..]].concat()
+        let account_names: HashSet<String> = hive.get_ignore_list(account).await?;
+        banned_account_names.extend(account_names);
     }
 
-    let banned_words: Vec<&str> = vec![ //TODO: make configurable through cli arg like banned accounts
-                                        "!PIZZA",
-                                        "!LUV",
-                                        "!ENGAGE",
+    Ok(banned_account_names)
+}
+
+async fn load_banned_words(args: &CLIARGS, database: &BeerDatabase) -> Result<BannedWords> {
+    let config = Config::load(&args.config)?;
+
+    let mut words: Vec<String> = vec![];
+
+    let sources = [
+        args.banned_words.as_str().split(',').map(str::to_string).collect(),
+        config.banned_words,
+        database.banned_words().await?,
     ];
 
-    let beerlover: Beerlover = Beerlover::new(banned_account_names.clone(), banned_words.clone(), args.trigger_word.clone(), args.share_ration.clone());
+    for word in sources.concat() {
+        let word = word.trim().to_string();
+        if !word.is_empty() && !words.contains(&word) {
+            words.push(word);
+        }
+    }
+
+    Ok(BannedWords::new(words, args.banned_words_case_insensitive, args.banned_words_regex))
+}
+
+async fn reload(args: &CLIARGS, hive: &mut Hive, database: &BeerDatabase, beerlover: &mut Beerlover) -> Result<()> {
+    let banned_words = load_banned_words(args, database).await?;
+    info!("Reloaded {} banned words", banned_words.words().len());
+    beerlover.set_banned_words(banned_words);
+
+    let banned_account_names = load_banned_accounts(args, hive).await?;
+    let (added, removed) = beerlover.set_banned_accounts(banned_account_names);
+    info!("Refreshed blacklist: {} added [{}], {} removed [{}]", added.len(), added.join(","), removed.len(), removed.join(","));
+
+    Ok(())
+}
+
+async fn process_block(rules: &RuleEngine, mut context: RuleContext<'_>, cur_block: i64) -> Result<Vec<StakingQueueEntry>> {
+    let (args, database, beerlover, block) = (context.args, context.database, context.beerlover, context.block);
+    info!("Block {} has {:?} transactions!", cur_block, &block.transactions.len());
+
+    let mut entries: Vec<StakingQueueEntry> = vec![];
+    let mut block_comments: HashSet<(String, String)> = HashSet::new();
+
+    for tx in block.transactions.iter() {
+        // Written before the comments so a comment later in the block already sees the change
+        for (account, opted_out) in beerlover.filter_optouts(tx) {
+            info!("@{} {}", account, if opted_out { "opted out" } else { "opted in" });
+            database.set_opted_out(account, opted_out).await?;
+        }
+
+        let posts: HivePostList = beerlover.filter_operations(tx);
+
+        for mut post in posts {
+            if !database.already_processed(post.tx_id.clone(), post.op_index).await? {
+                // Comment operations are also used for edits, every comment is evaluated only once
+                let comment = (post.author.clone(), post.permlink.clone());
+                if block_comments.contains(&comment) || database.already_processed_permlink(post.author.clone(), post.permlink.clone()).await? {
+                    info!("Ignoring edit of already processed comment @{}/{}", post.author, post.permlink);
+                    continue;
+                }
+                block_comments.insert(comment);
+
+                rules.evaluate(&mut post, &mut context).await?;
+
+                let entry = StakingQueueEntry::from(post, args);
+                info!("New Queue Entry: [{:?}] {}", &entry.action, entry);
+
+                match entry.action {
+                    StakingQueueAction::StakeAndComment => context.pending.record(&entry),
+                    StakingQueueAction::NotEnoughTokenInAccount => context.pending.unfunded += entry.amount,
+                    _ => {}
+                }
+
+                if entry.reasons.len() > 1 {
+                    info!("All reasons: {:?}", &entry.reasons);
+                }
+
+                entries.push(entry);
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Retries rewards that were rejected while the bot account was empty, oldest first.
+/// They are promoted once the balance covers them and the stake and share rules still pass.
+async fn retry_unfunded(rules: &RuleEngine, mut context: RuleContext<'_>) -> Result<()> {
+    let database = context.database;
+    context.retrying = true;
+
+    for entry in database.queue_entries(StakingQueueAction::NotEnoughTokenInAccount).await? {
+        let mut post = entry.post();
+        rules.recheck(&mut post, &mut context).await?;
+
+        // Younger rewards wait until the older ones are covered
+        if post.action == StakingQueueAction::NotEnoughTokenInAccount {
+            break;
+        }
+
+        info!("Retried unfunded reward: [{:?}] {}", &post.action, entry);
+        database.update_action(&entry, StakingQueueAction::NotEnoughTokenInAccount, post.action, post.reasons).await?;
+    }
+
+    Ok(())
+}
+
+async fn process(args: &CLIARGS, client: reqwest::Client, database: BeerDatabase) -> Result<()> {
+    let counter = Counter::new(0);
+    let he_counter = Counter::new(0);
+
+    let rpc_options: RpcOptions = RpcOptions {
+        retries: args.rpc_retries,
+        timeout: Duration::from_secs(args.rpc_timeout),
+        cooldown: Duration::from_secs(args.rpc_cooldown),
+    };
+
+    let rpc_hosts: Vec<String> = args.rpc_host.as_str().split(',').map(str::to_string).collect();
+    let he_rpc_hosts: Vec<String> = args.he_rpc_host.as_str().split(',').map(str::to_string).collect();
+
+    let mut hive: Hive = Hive::new(rpc_hosts.clone(), client.clone(), counter, rpc_options.clone())?;
+    let fetch_hive: Hive = Hive::new(rpc_hosts, client.clone(), Counter::new(0), rpc_options.clone())?;
+    let mut hive_engine: HiveEngine = HiveEngine::new(he_rpc_hosts, client.clone(), he_counter, rpc_options)?;
+
+    let banned_account_names: HashSet<String> = load_banned_accounts(args, &mut hive).await?;
+    let banned_words: BannedWords = load_banned_words(args, &database).await?;
+
+    let config: Config = Config::load(&args.config)?;
+
+    // Without a command table in the config file the trigger word and reward amount from the CLI are used
+    let commands: Vec<Command> = if config.commands.is_empty() {
+        vec![Command {
+            command: args.trigger_word.clone(),
+            amount: args.reward_amount,
+            share_cost: 1,
+            daily_limit: None,
+            max_count: 1,
+        }]
+    } else {
+        config.commands.clone()
+    };
+
+    for command in commands.iter() {
+        command.validate()?;
+    }
+
+    // Stake operations are rejected by Hive Engine if the quantity has more decimal places than the token
+    let precision = hive_engine.token_precision(args.he_token_symbol.clone()).await?;
+    let commands: Vec<Command> = commands.into_iter()
+        .map(|command| Ok(Command { amount: command.amount.with_precision(precision)?, ..command }))
+        .collect::<Result<Vec<Command>>>()?;
+
+    // Without share tiers in the config file every stake gets one share per `--share-ration` token
+    let share_tiers: Vec<ShareTier> = if config.share_tiers.is_empty() {
+        vec![ShareTier {
+            min_stake: TokenAmount::default(),
+            ratio: Some(args.share_ration),
+            max_shares: None,
+        }]
+    } else {
+        config.share_tiers.clone()
+    };
+
+    if let Some(tier) = share_tiers.iter().find(|tier| tier.ratio.is_none() && tier.max_shares.is_none()) {
+        return Err(Error::Config(format!("Share tier from {} needs a ratio or max_shares", tier.min_stake)));
+    }
+
+    let mut beerlover: Beerlover = Beerlover::new(banned_account_names.clone(), banned_words, commands, share_tiers, config.max_daily_shares, args.stake_mode.clone());
+
+    let rules: RuleEngine = if config.rules.is_empty() {
+        RuleEngine::from_config(&RuleConfig::defaults(args))
+    } else {
+        RuleEngine::from_config(&config.rules)
+    };
+
+    let state: Box<dyn StateStore> = match args.state_backend {
+        StateBackend::Mongo => Box::new(MongoStateStore::new(database.state_collection(), args.instance_name.clone(), Some(FileStateStore::new(args.state_file.clone())))),
+        StateBackend::File => Box::new(FileStateStore::new(args.state_file.clone()))
+    };
 
     if args.set_block_state > 0 {
         info!("Setting block state to: {}", &args.set_block_state);
-        beerlover.set_start_block(args.set_block_state);
-        std::process::exit(0);
+        return state.set_start_block(args.set_block_state).await;
     }
 
-    let start = beerlover.get_start_block();
-    let hive_height = hive.get_head_block().await;
+    // The filter does not learn the entries of other processors, MongoDB is asked as soon as one may exist
+    if args.seen_filter && args.follow {
+        if database.other_instances(&args.instance_name).await? {
+            warn!("Another instance uses the database, not using the seen filter");
+        } else {
+            database.load_seen().await?;
+        }
+    }
+
+    let sync_options: SyncOptions = SyncOptions {
+        follow: args.follow,
+        irreversible: args.irreversible,
+        block_lag: args.block_lag,
+        batch_size: args.batch_size,
+        prefetch: args.prefetch,
+    };
 
-    if start > hive_height {
+    let start = state.get_start_block().await?;
+    let hive_height = sync_target(&mut hive, &sync_options).await?;
+
+    if start > hive_height && !args.follow {
         warn!("Exiting because start > hive_height: {} > {}", start, hive_height);
-        std::process::exit(0)
+        return Ok(());
     }
 
-    let mut block_counter = Counter::new(start);
+    let mut last_reload = Instant::now();
+    let mut last_funding_check: Option<Instant> = None;
+    let reload_interval = Duration::from_secs(args.reload_interval);
+    let reload_requested = Arc::new(AtomicBool::new(false));
+
+    if args.follow {
+        let reload_requested = reload_requested.clone();
+        let mut hangup = signal(SignalKind::hangup()).map_err(|e| Error::Config(format!("Failed to listen for SIGHUP: {}", e)))?;
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading blacklist");
+                reload_requested.store(true, Ordering::SeqCst);
+            }
+        });
+    }
 
     if args.debug_info {
         debug!("=============== BEERLOVER CONFIG ===============\n");
@@ -130,92 +578,97 @@ async fn main() {
         debug!("Hive Account: \t\t{}", args.hive_account.clone());
         debug!("Hive RPC Host: \t\t{}", args.rpc_host.clone());
         debug!("Hive Engine RPC Host: \t{}", args.he_rpc_host.clone());
+        debug!("RPC Retries: \t\t{} (timeout: {}s, cooldown: {}s)", &args.rpc_retries, &args.rpc_timeout, &args.rpc_cooldown);
         debug!("Hive Engine Token Symbol: \t{}", args.he_token_symbol.clone());
-        debug!("Hive Banned Accounts: \t{}", banned_account_names.clone().join(",").to_string());
+        debug!("Hive Banned Accounts: \t{}", banned_account_names.iter().cloned().collect::<Vec<String>>().join(","));
+        debug!("Hive Banned Words: \t\t{}", beerlover.banned_words().words().join(","));
 
         debug!("MongoDB URI: \t\t{}", &args.mongodb_uri);
         debug!("MongoDB Database: \t\t{}", &args.mongodb_name);
         debug!("MongoDB Collection: \t\t{}", &args.mongodb_collection);
 
-        debug!("Beerlover Reward Amount: \t{} {}", &args.reward_amount, &args.he_token_symbol);
-        debug!("Beerlover Share Ratio: \t{}", &args.share_ration);
-        debug!("Beerlover Trigger Word: \t{}", &args.trigger_word);
+        debug!("Beerlover Stake Mode: \t{:?} (max daily shares: {:?})", &args.stake_mode, &config.max_daily_shares);
+        for tier in beerlover.share_tiers() {
+            debug!("Beerlover Share Tier: \t{}+ {} (ratio: {:?}, max shares: {:?})", &tier.min_stake, &args.he_token_symbol, tier.ratio.map(|ratio| ratio.to_string()), &tier.max_shares);
+        }
+        for command in beerlover.commands() {
+            debug!("Beerlover Command: \t\t{} {} {} (shares: {}, daily limit: {:?}, max count: {})", &command.command, &command.amount, &args.he_token_symbol, &command.share_cost, &command.daily_limit, &command.max_count);
+        }
+        debug!("Beerlover Edit Policy: \t{:?}", &args.edit_policy);
+        debug!("Beerlover Rules: \t\t{}", rules.names().join(","));
+        debug!("Beerlover Allow-List: \t{}", &args.allow_list);
 
+        debug!("Beerlover State: \t\t{:?} (instance: {}, file: {})", &args.state_backend, &args.instance_name, args.state_file.display());
+        debug!("Beerlover Cache: \t\t{} operations (seen filter: {})", &args.processed_cache_size, database.has_seen_filter());
         debug!("Beerlover Start Block: \t{}", &start);
-        debug!("Beerlover Hive Head Block: \t{}\n", &hive_height);
+        debug!("Beerlover Hive Head Block: \t{}", &hive_height);
+        debug!("Beerlover Follow: \t\t{} (lag: {}, irreversible: {})", &args.follow, &args.block_lag, &args.irreversible);
+        debug!("Beerlover Batch Size: \t{} (prefetch: {})\n", &args.batch_size, &args.prefetch);
     }
 
     debug!("=============== BEERLOVER BEGIN ===============");
 
+    let mut blocks = BlockFetcher::new(fetch_hive, sync_options).spawn(start, hive_height);
+
     loop {
-        let cur_block = block_counter.next();
+        if args.follow && (last_reload.elapsed() >= reload_interval || reload_requested.swap(false, Ordering::SeqCst)) {
+            // A failed reload keeps the previous lists
+            if let Err(e) = reload(args, &mut hive, &database, &mut beerlover).await {
+                warn!("Failed to reload blacklist: {}", e);
+            }
 
-        if cur_block > hive_height {
-            info!("Finished importing to headblock!");
-            break;
-        }
+            if database.has_seen_filter() && database.other_instances(&args.instance_name).await.unwrap_or(true) {
+                warn!("Another instance uses the database, dropping the seen filter");
+                database.forget_seen();
+            }
 
-        let block_data = hive.get_block(cur_block).await;
+            last_reload = Instant::now();
+        }
 
-        let trx = match block_data["result"]["transactions"].as_array() {
-            Some(trx) => trx.to_owned(),
-            _ => {
-                beerlover.set_start_block(cur_block);
-                continue;
+        let (cur_block, block) = match blocks.recv().await {
+            Some(Ok(block)) => block,
+            Some(Err(e)) => {
+                error!("Stopping, failed to fetch blocks after {}", state.get_start_block().await? - 1);
+                return Err(e);
+            }
+            None => {
+                info!("Finished importing to headblock!");
+                break;
             }
         };
 
-        info!("Block {} has {:?} transactions!", cur_block, &trx.len());
-
-        for tx in trx {
-            let posts: HivePostList = beerlover.filter_operations(tx["operations"].to_owned(), tx["transaction_id"].as_str().unwrap().to_string().to_owned());
-
-            for post in posts {
-                if database.already_processed(post.tx_id.clone()).await == false {
-                    if post.action == StakingQueueAction::StakeAndComment {
-                        let author_beer_balance = hive_engine.stake(post.author.clone(), args.he_token_symbol.clone()).await;
-                        let author_max_shares = beerlover.maxium_shares(author_beer_balance.clone());
-                        let share_count = database.transfer_count(post.author.clone()).await;
-                        let pending_share_count = database.pending_transfer_count(post.author.clone()).await;
-
-                        let absolute_shares: i64 = share_count + pending_share_count;
-
-                        if author_max_shares > 0 {
-                            if absolute_shares < author_max_shares {
-                                let entry: StakingQueueEntry = StakingQueueEntry {
-                                    from: post.author,
-                                    to: post.parent_author,
-                                    amount: args.reward_amount.clone(),
-                                    symbol: args.he_token_symbol.clone(),
-                                    permlink: post.parent_permlink.clone(),
-                                    from_permlink: post.permlink.clone(),
-                                    from_tx: post.tx_id.clone(),
-                                    action: StakingQueueAction::StakeAndComment,
-                                };
-
-                                info!("New Queue Entry: [{:?}] {}",StakingQueueAction::StakeAndComment, entry);
-
-                                database.add_to_queue(entry).await;
-                            } else {
-                                let entry = StakingQueueEntry::from(post, &args, StakingQueueAction::SharesExceeded);
-                                info!("New Queue Entry: [{:?}] {}",StakingQueueAction::SharesExceeded, entry);
-                                database.add_to_queue(entry).await;
-                            }
-                        } else {
-                            let entry = StakingQueueEntry::from(post, &args, StakingQueueAction::NotEnoughStake);
-                            info!("New Queue Entry: [{:?}] {}",StakingQueueAction::NotEnoughStake, entry);
-                            database.add_to_queue(entry).await;
-                        }
-                    } else {
-                        let action = post.action.clone();
-                        let entry = StakingQueueEntry::from(post, &args, action.clone());
-                        info!("New Queue Entry: [{:?}] {}", &action, entry);
-                        database.add_to_queue(entry).await;
-                    }
-                }
+        // Unfunded rewards are promoted before the new comments of the block get a chance
+        if last_funding_check.is_none_or(|checked| checked.elapsed() >= reload_interval) {
+            let context = RuleContext::new(args, &mut hive, &mut hive_engine, &database, &beerlover, &block);
+
+            if let Err(e) = retry_unfunded(&rules, context).await {
+                warn!("Failed to retry unfunded rewards: {}", e);
             }
+
+            last_funding_check = Some(Instant::now());
         }
 
-        beerlover.set_start_block(cur_block);
+        loop {
+            let context = RuleContext::new(args, &mut hive, &mut hive_engine, &database, &beerlover, &block);
+
+            let result = match process_block(&rules, context, cur_block).await {
+                Ok(entries) => state.commit_block(&database, entries, cur_block).await,
+                Err(e) => Err(e)
+            };
+
+            match result {
+                Ok(()) => break,
+                Err(e) if args.follow && e.is_retryable() => {
+                    warn!("Retrying block {}: {}", cur_block, e);
+                    tokio::time::sleep(BLOCK_INTERVAL).await;
+                }
+                Err(e) => {
+                    error!("Stopping at block {}, last processed block is {}", cur_block, cur_block - 1);
+                    return Err(e);
+                }
+            }
+        }
     }
+
+    Ok(())
 }
@@ -1,121 +1,729 @@
 use std::fmt;
 use std::fmt::{Formatter};
-use mongodb::{bson::doc, bson::DateTime, Collection, Client};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use mongodb::{bson::doc, bson::DateTime, bson::Document, Collection, Client, ClientSession, IndexModel};
+use mongodb::error::{ErrorKind, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use mongodb::options::{FindOptions, IndexOptions, UpdateOptions};
+use mongodb::bson::Bson;
 use serde::{Deserialize, Serialize};
 use crate::{CLIARGS, HivePost};
+use crate::amount::TokenAmount;
+use crate::cache::{BloomFilter, LruSet};
+use crate::error::Result;
+use crate::state::{BlockState, state_update};
 
 const ONE_DAY: i64 = 60 * 60 * 24;
+const TRANSACTION_ATTEMPTS: u32 = 3;
+const SEEN_FALSE_POSITIVE_RATE: f64 = 0.01;
+/// Room for new operations on top of the stored ones before the false positive rate of the filter rises
+const SEEN_HEADROOM: usize = 1_000_000;
 
-#[allow(dead_code)]
-struct BeerTransfer {
-    from: String,
-    to: String,
-    permlinkFrom: String,
-    txIdFrom: String,
-    txIdTo: String,
-    createdAt: DateTime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BeerTransfer {
+    pub from: String,
+    pub to: String,
+    pub permlinkFrom: String,
+    pub txIdFrom: String,
+    #[serde(default)]
+    pub opIndexFrom: i64,
+    pub txIdTo: String,
+    #[serde(default)]
+    pub amount: Option<TokenAmount>,
+    #[serde(default)]
+    pub command: String,
+    #[serde(default = "default_shares")]
+    pub shares: i64,
+    /// Reply comment, None until it was broadcast
+    #[serde(default)]
+    pub replyTx: Option<String>,
+    /// True while the reply comment still has to be broadcast
+    #[serde(default)]
+    pub replyPending: bool,
+    pub createdAt: DateTime
+}
+
+impl BeerTransfer {
+    pub fn from(entry: &StakingQueueEntry, tx_id: String) -> BeerTransfer {
+        BeerTransfer {
+            from: entry.from.clone(),
+            to: entry.to.clone(),
+            permlinkFrom: entry.from_permlink.clone(),
+            txIdFrom: entry.from_tx.clone(),
+            opIndexFrom: entry.from_op_index,
+            txIdTo: tx_id,
+            amount: Some(entry.amount),
+            command: entry.command.clone(),
+            shares: entry.shares,
+            replyTx: None,
+            replyPending: true,
+            createdAt: DateTime::now()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BannedWord {
+    pub word: String
+}
+
+/// An account on the opt-out list or the allow-list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListedAccount {
+    #[serde(rename = "_id")]
+    pub account: String,
+    pub createdAt: DateTime
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all="lowercase")]
 pub enum StakingQueueAction {
     StakeAndComment, //Everything okay. stake token and make comment
+    Broadcasting, // The stake was sent to the broadcast API, kept until the transfer is recorded
     NotEnoughTokenInAccount, // The main accounts does not have enougth token and needs a refill
     NotEnoughStake, // The user has not enough token staked
     SharesExceeded, // The user exceeded their 24 hour limit
     Blocked, // The user is blocked from using the service
     BlockedWord, // Post contains blacklisted word
     SelfReward, // User tries to give Beer to themselves
+    DailyLimitExceeded, // The user exceeded the 24 hour limit of the command
+    RecipientLimitExceeded, // The recipient received the maximum amount in 24 hours
+    PairLimitExceeded, // The user rewarded the same recipient too often in 24 hours
+    OptedOut, // The recipient does not want to receive token
+    NotAllowed, // The user is not on the allow-list
+    Edited, // The trigger word was added by editing an existing comment
     Invalid // Used when the trigger word wasn't found. Not stored to db.
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StakingQueueEntry {
     pub to: String,
-    pub amount: String,
+    pub amount: TokenAmount,
     pub symbol: String,
     pub from: String,
     pub permlink: String,
     pub from_permlink: String,
     pub from_tx: String,
-    pub action: StakingQueueAction
+    #[serde(default)]
+    pub from_op_index: i64,
+    #[serde(default)]
+    pub command: String,
+    #[serde(default = "default_shares")]
+    pub shares: i64,
+    pub action: StakingQueueAction,
+    /// All rejection reasons that matched, `action` is the first of them.
+    /// The balance is only checked for otherwise eligible comments.
+    #[serde(default)]
+    pub reasons: Vec<StakingQueueAction>
+}
+
+/// Entries written before commands had a share cost used one share each
+fn default_shares() -> i64 {
+    1
 }
 
 impl fmt::Display for StakingQueueEntry {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "Stake {} {}\tFrom: {}\tTo: {}\tPermlink: {}\tFrom TX: {}", self.amount, self.symbol, self.from, self.to, self.permlink, self.from_tx)
+        write!(f, "Stake {} {}\tFrom: {}\tTo: {}\tPermlink: {}\tFrom TX: {}/{}", self.amount, self.symbol, self.from, self.to, self.permlink, self.from_tx, self.from_op_index)
     }
 }
 
 impl StakingQueueEntry {
-    pub fn from(post: HivePost, args: &CLIARGS, action: StakingQueueAction) -> StakingQueueEntry {
+    /// The comment of a queued entry for checking the rules again. The body is not stored, so it is empty.
+    pub fn post(&self) -> HivePost {
+        HivePost {
+            author: self.from.clone(),
+            permlink: self.from_permlink.clone(),
+            parent_author: self.to.clone(),
+            parent_permlink: self.permlink.clone(),
+            body: String::new(),
+            text: String::new(),
+            tx_id: self.from_tx.clone(),
+            op_index: self.from_op_index,
+            command: self.command.clone(),
+            amount: self.amount,
+            shares: self.shares,
+            action: StakingQueueAction::StakeAndComment,
+            reasons: vec![]
+        }
+    }
+
+    pub fn from(post: HivePost, args: &CLIARGS) -> StakingQueueEntry {
         StakingQueueEntry {
             from: post.author,
             to: post.parent_author,
-            amount: args.reward_amount.clone(),
+            amount: post.amount,
             symbol: args.he_token_symbol.clone(),
             permlink: post.parent_permlink,
             from_permlink: post.permlink,
             from_tx: post.tx_id,
-            action
+            from_op_index: post.op_index,
+            command: post.command,
+            shares: post.shares,
+            action: post.action,
+            reasons: post.reasons
         }
     }
 }
 
+/// Matches a single operation of a transaction. Documents written before the operation index
+/// was stored only ever referenced the first operation, so they are treated as index 0.
+fn operation_filter(tx_field: &str, tx_id: String, op_field: &str, op_index: i64) -> Document {
+    if op_index == 0 {
+        doc! {
+            tx_field: tx_id,
+            "$or": [
+                { op_field: 0i64 },
+                { op_field: { "$exists": false } }
+            ]
+        }
+    } else {
+        doc! {
+            tx_field: tx_id,
+            op_field: op_index
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct QueueKey {
+    from_tx: String,
+    #[serde(default)]
+    from_op_index: i64,
+    from: String,
+    from_permlink: String
+}
+
+#[derive(Deserialize)]
+struct TransferKey {
+    txIdFrom: String,
+    #[serde(default)]
+    opIndexFrom: i64,
+    from: String,
+    permlinkFrom: String
+}
+
+fn operation_key(tx_id: &str, op_index: i64) -> (u8, &str, String) {
+    (0, tx_id, op_index.to_string())
+}
+
+fn comment_key<'a>(author: &'a str, permlink: &'a str) -> (u8, &'a str, String) {
+    (1, author, permlink.to_string())
+}
+
+/// Rewards that are queued or being broadcast, both are not in the transfer collection yet
+fn pending_actions() -> Document {
+    doc! { "$in": ["stakeandcomment", "broadcasting"] }
+}
+
+fn yesterday() -> DateTime {
+    DateTime::from_millis((chrono::Utc::now().timestamp() - ONE_DAY) * 1000)
+}
+
+async fn sum_shares<T>(collection: &Collection<T>, filter: Document) -> Result<i64> {
+    let mut cursor = collection.aggregate(vec![
+        doc! { "$match": filter },
+        doc! { "$group": { "_id": Bson::Null, "shares": { "$sum": { "$ifNull": ["$shares", 1i64] } } } }
+    ], None).await?;
+
+    if cursor.advance().await? {
+        let result = cursor.deserialize_current()?;
+        return Ok(match result.get("shares") {
+            Some(Bson::Int64(shares)) => *shares,
+            Some(Bson::Int32(shares)) => *shares as i64,
+            _ => 0
+        });
+    }
+
+    Ok(0)
+}
+
+#[derive(Deserialize)]
+struct AmountRow {
+    amount: Option<TokenAmount>
+}
+
+/// Sums the amounts exactly instead of with `$sum`, which adds them as doubles.
+/// `fallback` is used for documents without an amount, transfers written before the amount was stored.
+async fn sum_amount<T>(collection: &Collection<T>, filter: Document, fallback: TokenAmount) -> Result<TokenAmount> {
+    let options = FindOptions::builder().projection(doc! { "amount": 1 }).build();
+    let mut cursor = collection.clone_with_type::<AmountRow>().find(filter, options).await?;
+    let mut sum = TokenAmount::zero(fallback.precision());
+
+    while cursor.advance().await? {
+        sum += cursor.deserialize_current()?.amount.unwrap_or(fallback);
+    }
+
+    Ok(sum)
+}
+
 pub struct DatabaseOptions {
     pub uri: String,
     pub db_name: String,
     pub collection_name: String,
-    pub queue_collection_name: String
+    pub queue_collection_name: String,
+    pub banned_words_collection_name: String,
+    pub state_collection_name: String,
+    pub optout_collection_name: String,
+    pub allowlist_collection_name: String,
+    pub processed_cache_size: usize
 }
 
 pub struct Database {
     client: Client,
+    transactions: AtomicBool,
+    processed: Mutex<LruSet<(String, i64)>>,
+    seen: Mutex<Option<BloomFilter>>,
     collection: Collection<BeerTransfer>,
-    queue: Collection<StakingQueueEntry>
+    queue: Collection<StakingQueueEntry>,
+    banned_words: Collection<BannedWord>,
+    state: Collection<BlockState>,
+    optouts: Collection<ListedAccount>,
+    allowlist: Collection<ListedAccount>
 }
 
 impl Database {
-    pub async fn new(options: DatabaseOptions) -> Database {
-        let client = Client::with_uri_str(&options.uri).await.unwrap();
+    pub async fn new(options: DatabaseOptions) -> Result<Database> {
+        let client = Client::with_uri_str(&options.uri).await?;
         let database = client.database(&options.db_name);
         let collection = database.collection::<BeerTransfer>(&options.collection_name);
         let queue = database.collection::<StakingQueueEntry>(&options.queue_collection_name);
+        let banned_words = database.collection::<BannedWord>(&options.banned_words_collection_name);
+        let state = database.collection::<BlockState>(&options.state_collection_name);
+        let optouts = database.collection::<ListedAccount>(&options.optout_collection_name);
+        let allowlist = database.collection::<ListedAccount>(&options.allowlist_collection_name);
+
+        let index = IndexModel::builder()
+            .keys(doc! { "from_tx": 1, "from_op_index": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+
+        if let Err(e) = queue.create_index(index, None).await {
+            warn!("Failed to create unique queue index, remove duplicate queue entries to make replays idempotent: {}", e);
+        }
+
+        queue.create_index(IndexModel::builder().keys(doc! { "from": 1, "from_permlink": 1 }).build(), None).await?;
 
-        Database {
+        Ok(Database {
             client,
+            transactions: AtomicBool::new(true),
+            processed: Mutex::new(LruSet::new(options.processed_cache_size)),
+            seen: Mutex::new(None),
             collection,
-            queue
+            queue,
+            banned_words,
+            state,
+            optouts,
+            allowlist
+        })
+    }
+
+    async fn write_block(&self, entries: &[StakingQueueEntry], checkpoint: Option<(&str, i64)>, mut session: Option<&mut ClientSession>) -> Result<()> {
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        // Upserts keyed by the operation keep replays of a block from duplicating entries
+        for entry in entries {
+            let filter = doc! { "from_tx": entry.from_tx.clone(), "from_op_index": entry.from_op_index };
+            let update = doc! { "$setOnInsert": mongodb::bson::to_document(entry).map_err(mongodb::error::Error::from)? };
+
+            match session.as_deref_mut() {
+                Some(session) => self.queue.update_one_with_session(filter, update, options.clone(), session).await?,
+                None => self.queue.update_one(filter, update, options.clone()).await?
+            };
+        }
+
+        if let Some((instance, block)) = checkpoint {
+            let filter = doc! { "_id": instance };
+
+            match session {
+                Some(session) => self.state.update_one_with_session(filter, state_update(block), options, session).await?,
+                None => self.state.update_one(filter, state_update(block), options).await?
+            };
         }
+
+        Ok(())
     }
 
-    pub async fn already_processed(&self, tx_id: String) -> bool {
-        self.collection.count_documents(doc! {
-            "txIdFrom": tx_id
-        }, None).await.unwrap() > 0
+    async fn write_block_transaction(&self, entries: &[StakingQueueEntry], checkpoint: Option<(&str, i64)>) -> mongodb::error::Result<()> {
+        let mut session = self.client.start_session(None).await?;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            session.start_transaction(None).await?;
+
+            let result = match self.write_block(entries, checkpoint, Some(&mut session)).await {
+                Ok(()) => session.commit_transaction().await,
+                Err(crate::error::Error::Database(e)) => {
+                    session.abort_transaction().await.ok();
+                    Err(e)
+                }
+                Err(e) => {
+                    session.abort_transaction().await.ok();
+                    return Err(mongodb::error::Error::custom(e.to_string()));
+                }
+            };
+
+            match result {
+                Err(e) if attempt < TRANSACTION_ATTEMPTS && (e.contains_label(TRANSIENT_TRANSACTION_ERROR) || e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)) => {
+                    warn!("Retrying block transaction: {}", e);
+                }
+                result => return result
+            }
+        }
     }
 
-    pub async fn add_to_queue(&self, entry: StakingQueueEntry) {
-        self.queue.insert_one(entry, None).await.ok();
+    /// Stores the queue entries of a block together with the checkpoint of a MongoDB state store
+    /// in one transaction. Standalone servers without transaction support write them one after another.
+    pub async fn commit_block(&self, entries: Vec<StakingQueueEntry>, checkpoint: Option<(&str, i64)>) -> Result<()> {
+        let mut committed = false;
+
+        if self.transactions.load(Ordering::SeqCst) {
+            match self.write_block_transaction(&entries, checkpoint).await {
+                Ok(()) => committed = true,
+                Err(e) if matches!(*e.kind, ErrorKind::Command(ref c) if c.code == 20) => {
+                    warn!("MongoDB does not support transactions, committing blocks without them: {}", e);
+                    self.transactions.store(false, Ordering::SeqCst);
+                }
+                Err(e) => return Err(e.into())
+            }
+        }
+
+        if !committed {
+            self.write_block(&entries, checkpoint, None).await?;
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(seen) = seen.as_mut() {
+            for entry in entries.iter() {
+                seen.insert(&operation_key(&entry.from_tx, entry.from_op_index));
+                seen.insert(&comment_key(&entry.from, &entry.from_permlink));
+            }
+        }
+
+        let mut processed = self.processed.lock().unwrap();
+        for entry in entries {
+            processed.insert((entry.from_tx, entry.from_op_index));
+        }
+
+        Ok(())
     }
 
-    pub async fn pending_transfer_count(&self, account: String) -> i64 {
-        self.queue.count_documents(doc! {
-            "from": account,
-            "action": "stakeandcomment"
-        }, None).await.unwrap_or(0) as i64
+    /// True if another instance stored a checkpoint in the last 24 hours, so another processor may write to the queue
+    pub async fn other_instances(&self, instance: &str) -> Result<bool> {
+        Ok(self.state.count_documents(doc! {
+            "_id": { "$ne": instance },
+            "updatedAt": { "$gt": yesterday() }
+        }, None).await? > 0)
+    }
+
+    pub fn state_collection(&self) -> Collection<BlockState> {
+        self.state.clone()
+    }
+
+    pub async fn already_transferred(&self, tx_id: String, op_index: i64) -> Result<bool> {
+        Ok(self.collection.count_documents(operation_filter("txIdFrom", tx_id, "opIndexFrom", op_index), None).await? > 0)
+    }
+
+    /// True if the comment is in the queue with any action or was already transferred
+    pub async fn already_processed_permlink(&self, author: String, permlink: String) -> Result<bool> {
+        if !self.maybe_seen(&comment_key(&author, &permlink)) {
+            return Ok(false);
+        }
+
+        Ok(self.queue.count_documents(doc! {
+            "from": author.clone(),
+            "from_permlink": permlink.clone()
+        }, None).await? > 0 || self.collection.count_documents(doc! {
+            "from": author,
+            "permlinkFrom": permlink
+        }, None).await? > 0)
+    }
+
+    /// True if the operation is in the queue with any action or was already transferred
+    /// False if the key is definitely not stored, MongoDB has to be asked otherwise
+    fn maybe_seen<K: Hash>(&self, key: &K) -> bool {
+        match self.seen.lock().unwrap().as_ref() {
+            Some(seen) => seen.contains(key),
+            None => true
+        }
+    }
+
+    pub fn has_seen_filter(&self) -> bool {
+        self.seen.lock().unwrap().is_some()
+    }
+
+    /// Drops the bloom filter, every duplicate check asks MongoDB again
+    pub fn forget_seen(&self) {
+        *self.seen.lock().unwrap() = None;
+    }
+
+    /// Loads the operations and comments of the queue and the transfers into a bloom filter,
+    /// so new comments, the common case, are recognised without a query.
+    /// The filter only learns the entries this process commits afterwards.
+    pub async fn load_seen(&self) -> Result<()> {
+        let stored = self.queue.estimated_document_count(None).await? + self.collection.estimated_document_count(None).await?;
+        let mut seen = BloomFilter::new(stored as usize + SEEN_HEADROOM, SEEN_FALSE_POSITIVE_RATE);
+
+        let options = FindOptions::builder().projection(doc! { "from_tx": 1, "from_op_index": 1, "from": 1, "from_permlink": 1 }).build();
+        let mut cursor = self.queue.clone_with_type::<QueueKey>().find(None, options).await?;
+        while cursor.advance().await? {
+            let key = cursor.deserialize_current()?;
+            seen.insert(&operation_key(&key.from_tx, key.from_op_index));
+            seen.insert(&comment_key(&key.from, &key.from_permlink));
+        }
+
+        let options = FindOptions::builder().projection(doc! { "txIdFrom": 1, "opIndexFrom": 1, "from": 1, "permlinkFrom": 1 }).build();
+        let mut cursor = self.collection.clone_with_type::<TransferKey>().find(None, options).await?;
+        while cursor.advance().await? {
+            let key = cursor.deserialize_current()?;
+            seen.insert(&operation_key(&key.txIdFrom, key.opIndexFrom));
+            seen.insert(&comment_key(&key.from, &key.permlinkFrom));
+        }
+
+        info!("Loaded {} processed operations", stored);
+        *self.seen.lock().unwrap() = Some(seen);
+
+        Ok(())
+    }
+
+    pub async fn already_processed(&self, tx_id: String, op_index: i64) -> Result<bool> {
+        let key = (tx_id.clone(), op_index);
+
+        if self.processed.lock().unwrap().contains(&key) {
+            return Ok(true);
+        }
+
+        if !self.maybe_seen(&operation_key(&tx_id, op_index)) {
+            return Ok(false);
+        }
+
+        let processed = self.queue.count_documents(operation_filter("from_tx", tx_id.clone(), "from_op_index", op_index), None).await? > 0
+            || self.already_transferred(tx_id, op_index).await?;
+
+        if processed {
+            self.processed.lock().unwrap().insert(key);
+        }
+
+        Ok(processed)
+    }
+
+    pub async fn banned_words(&self) -> Result<Vec<String>> {
+        let mut words: Vec<String> = vec![];
+
+        let mut cursor = self.banned_words.find(None, None).await?;
+
+        while cursor.advance().await? {
+            words.push(cursor.deserialize_current()?.word);
+        }
+
+        Ok(words)
+    }
+
+    /// Entries with the given action, oldest first. `stakeandcomment` for rewards that were never sent
+    pub async fn queue_entries(&self, action: StakingQueueAction) -> Result<Vec<StakingQueueEntry>> {
+        let mut entries: Vec<StakingQueueEntry> = vec![];
+
+        let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let mut cursor = self.queue.find(doc! {
+            "action": mongodb::bson::to_bson(&action).map_err(mongodb::error::Error::from)?
+        }, options).await?;
+
+        while cursor.advance().await? {
+            entries.push(cursor.deserialize_current()?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Replaces the outcome of an entry that still has the action `current`
+    pub async fn update_action(&self, entry: &StakingQueueEntry, current: StakingQueueAction, action: StakingQueueAction, reasons: Vec<StakingQueueAction>) -> Result<()> {
+        let mut filter = operation_filter("from_tx", entry.from_tx.clone(), "from_op_index", entry.from_op_index);
+        filter.insert("action", mongodb::bson::to_bson(&current).map_err(mongodb::error::Error::from)?);
+
+        self.queue.update_one(filter, doc! {
+            "$set": {
+                "action": mongodb::bson::to_bson(&action).map_err(mongodb::error::Error::from)?,
+                "reasons": mongodb::bson::to_bson(&reasons).map_err(mongodb::error::Error::from)?
+            }
+        }, None).await?;
+
+        Ok(())
+    }
+
+    /// Claims a queued entry for broadcasting. Returns false if it is no longer queued, e.g. taken by another run.
+    pub async fn mark_broadcasting(&self, entry: &StakingQueueEntry) -> Result<bool> {
+        let mut filter = operation_filter("from_tx", entry.from_tx.clone(), "from_op_index", entry.from_op_index);
+        filter.insert("action", "stakeandcomment");
+
+        let result = self.queue.update_one(filter, doc! { "$set": { "action": "broadcasting" } }, None).await?;
+        Ok(result.modified_count == 1)
+    }
+
+    /// Puts an entry that definitely was not broadcast back into the queue
+    pub async fn unmark_broadcasting(&self, entry: &StakingQueueEntry) -> Result<()> {
+        let mut filter = operation_filter("from_tx", entry.from_tx.clone(), "from_op_index", entry.from_op_index);
+        filter.insert("action", "broadcasting");
+
+        self.queue.update_one(filter, doc! { "$set": { "action": "stakeandcomment" } }, None).await?;
+        Ok(())
+    }
+
+    pub async fn remove_from_queue(&self, entry: &StakingQueueEntry) -> Result<()> {
+        let mut filter = operation_filter("from_tx", entry.from_tx.clone(), "from_op_index", entry.from_op_index);
+        filter.insert("action", pending_actions());
+
+        self.queue.delete_one(filter, None).await?;
+        Ok(())
     }
 
-    pub async fn transfer_count(&self, account: String) -> i64 {
+    pub async fn add_transfer(&self, transfer: BeerTransfer) -> Result<()> {
+        self.collection.insert_one(transfer, None).await?;
+        Ok(())
+    }
 
-        let yesterday = DateTime::from_millis((chrono::Utc::now().timestamp() - ONE_DAY) * 1000);
+    /// Transfers from the last 24 hours whose reply comment was not broadcast yet, oldest first
+    pub async fn pending_replies(&self) -> Result<Vec<BeerTransfer>> {
+        let mut transfers: Vec<BeerTransfer> = vec![];
 
-        self.collection.count_documents(doc! {
+        let options = FindOptions::builder().sort(doc! { "createdAt": 1 }).build();
+        let mut cursor = self.collection.find(doc! {
+            "replyPending": true,
             "createdAt": {
-                "$gt": yesterday
+                "$gt": yesterday()
+            }
+        }, options).await?;
+
+        while cursor.advance().await? {
+            transfers.push(cursor.deserialize_current()?);
+        }
+
+        Ok(transfers)
+    }
+
+    pub async fn set_replied(&self, transfer: &BeerTransfer, tx_id: String) -> Result<()> {
+        self.collection.update_one(operation_filter("txIdFrom", transfer.txIdFrom.clone(), "opIndexFrom", transfer.opIndexFrom), doc! {
+            "$set": {
+                "replyTx": tx_id,
+                "replyPending": false
+            }
+        }, None).await?;
+
+        Ok(())
+    }
+
+    /// Amount of all rewards waiting in the queue
+    pub async fn pending_amount(&self, fallback: TokenAmount) -> Result<TokenAmount> {
+        sum_amount(&self.queue, doc! {
+            "action": pending_actions()
+        }, fallback).await
+    }
+
+    /// Amount of all rewards rejected because the bot account was empty, they are retried before new rewards
+    pub async fn unfunded_amount(&self, fallback: TokenAmount) -> Result<TokenAmount> {
+        sum_amount(&self.queue, doc! {
+            "action": "notenoughtokeninaccount"
+        }, fallback).await
+    }
+
+    pub async fn pending_shares(&self, account: String) -> Result<i64> {
+        sum_shares(&self.queue, doc! {
+            "from": account,
+            "action": pending_actions()
+        }).await
+    }
+
+    pub async fn transferred_shares(&self, account: String) -> Result<i64> {
+        sum_shares(&self.collection, doc! {
+            "createdAt": {
+                "$gt": yesterday()
             },
             "from": account
-        }, None).await.unwrap_or(0) as i64
+        }).await
+    }
+
+    /// Number of times the account used the command in the last 24 hours, including pending rewards
+    pub async fn command_count(&self, account: String, command: String) -> Result<i64> {
+        let pending = self.queue.count_documents(doc! {
+            "from": account.clone(),
+            "command": command.clone(),
+            "action": pending_actions()
+        }, None).await?;
+
+        let transferred = self.collection.count_documents(doc! {
+            "createdAt": {
+                "$gt": yesterday()
+            },
+            "from": account,
+            "command": command
+        }, None).await?;
+
+        Ok((pending + transferred) as i64)
+    }
+
+    /// Amount the account received in the last 24 hours, including pending rewards
+    pub async fn received_amount(&self, account: String, fallback: TokenAmount) -> Result<TokenAmount> {
+        let pending = sum_amount(&self.queue, doc! {
+            "to": account.clone(),
+            "action": pending_actions()
+        }, fallback).await?;
+
+        let transferred = sum_amount(&self.collection, doc! {
+            "createdAt": {
+                "$gt": yesterday()
+            },
+            "to": account
+        }, fallback).await?;
+
+        Ok(pending + transferred)
+    }
+
+    /// Number of rewards from one account to another in the last 24 hours, including pending rewards
+    pub async fn pair_count(&self, from: String, to: String) -> Result<i64> {
+        let pending = self.queue.count_documents(doc! {
+            "from": from.clone(),
+            "to": to.clone(),
+            "action": pending_actions()
+        }, None).await?;
+
+        let transferred = self.collection.count_documents(doc! {
+            "createdAt": {
+                "$gt": yesterday()
+            },
+            "from": from,
+            "to": to
+        }, None).await?;
+
+        Ok((pending + transferred) as i64)
+    }
+
+    /// Adds the account to the opt-out list or removes it again
+    pub async fn set_opted_out(&self, account: String, opted_out: bool) -> Result<()> {
+        if opted_out {
+            let options = UpdateOptions::builder().upsert(true).build();
+
+            self.optouts.update_one(doc! {
+                "_id": account
+            }, doc! {
+                "$setOnInsert": {
+                    "createdAt": DateTime::now()
+                }
+            }, options).await?;
+        } else {
+            self.optouts.delete_one(doc! {
+                "_id": account
+            }, None).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn is_opted_out(&self, account: String) -> Result<bool> {
+        Ok(self.optouts.count_documents(doc! { "_id": account }, None).await? > 0)
+    }
 
+    pub async fn is_allowed(&self, account: String) -> Result<bool> {
+        Ok(self.allowlist.count_documents(doc! { "_id": account }, None).await? > 0)
     }
 }
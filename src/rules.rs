@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use crate::{CLIARGS, EditPolicy};
+use crate::amount::TokenAmount;
+use crate::beerlover::Beerlover;
+use crate::error::Result;
+use crate::hive::{Hive, HiveEngine, HivePost, SignedBlock, TokenBalance};
+use crate::mongo::{Database, StakingQueueAction, StakingQueueEntry};
+
+/// Rewards accepted earlier in the block. They are not in the queue collection yet, so they are counted separately.
+#[derive(Debug, Default)]
+pub struct PendingRewards {
+    pub shares: HashMap<String, i64>,
+    pub commands: HashMap<(String, String), i64>,
+    pub received: HashMap<String, TokenAmount>,
+    pub pairs: HashMap<(String, String), i64>,
+    pub amount: TokenAmount,
+    /// Rewards of the block rejected because the bot account was empty
+    pub unfunded: TokenAmount,
+}
+
+impl PendingRewards {
+    pub fn record(&mut self, entry: &StakingQueueEntry) {
+        *self.shares.entry(entry.from.clone()).or_insert(0) += entry.shares;
+        *self.commands.entry((entry.from.clone(), entry.command.clone())).or_insert(0) += 1;
+        *self.received.entry(entry.to.clone()).or_default() += entry.amount;
+        self.amount += entry.amount;
+        *self.pairs.entry((entry.from.clone(), entry.to.clone())).or_insert(0) += 1;
+    }
+}
+
+/// Everything a rule may look at while checking the comments of a block
+pub struct RuleContext<'a> {
+    pub args: &'a CLIARGS,
+    pub hive: &'a mut Hive,
+    pub hive_engine: &'a mut HiveEngine,
+    pub database: &'a Database,
+    pub beerlover: &'a Beerlover,
+    pub block: &'a SignedBlock,
+    pub pending: PendingRewards,
+    /// Set while unfunded rewards are retried oldest first, no other unfunded reward is ahead of them then
+    pub retrying: bool,
+    balances: HashMap<String, TokenBalance>,
+}
+
+impl<'a> RuleContext<'a> {
+    pub fn new(args: &'a CLIARGS, hive: &'a mut Hive, hive_engine: &'a mut HiveEngine, database: &'a Database, beerlover: &'a Beerlover, block: &'a SignedBlock) -> RuleContext<'a> {
+        RuleContext {
+            args,
+            hive,
+            hive_engine,
+            database,
+            beerlover,
+            block,
+            pending: PendingRewards::default(),
+            retrying: false,
+            balances: HashMap::new(),
+        }
+    }
+
+    /// Token balance of the account, fetched once per block
+    pub async fn token_balance(&mut self, account: &str) -> Result<TokenBalance> {
+        if let Some(balance) = self.balances.get(account) {
+            return Ok(balance.clone());
+        }
+
+        let balance = self.hive_engine.token_balance(account.to_string(), self.args.he_token_symbol.clone()).await?;
+        self.balances.insert(account.to_string(), balance.clone());
+
+        Ok(balance)
+    }
+
+    /// Liquid token of `--hive-account`
+    pub async fn balance(&mut self) -> Result<TokenAmount> {
+        let account = self.args.hive_account.clone();
+        Ok(self.token_balance(&account).await?.balance)
+    }
+}
+
+/// A reward eligibility check
+#[async_trait]
+pub trait Rule: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Local rules only look at the comment itself and do not query Hive, Hive Engine or MongoDB
+    fn local(&self) -> bool {
+        false
+    }
+
+    /// Rules that reserve funds or change their own state only run while the comment is still eligible.
+    /// All other rules run for every comment so all matching reasons are recorded.
+    fn reserves(&self) -> bool {
+        false
+    }
+
+    /// Whether the rule is checked again when an unfunded reward is retried.
+    /// The local rules already passed and rules that depend on the block of the comment cannot be repeated later.
+    fn recheck(&self) -> bool {
+        !self.local()
+    }
+
+    /// Returns the rejection reason if the comment is not eligible
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>>;
+}
+
+/// The author or the recipient is on the blacklist
+pub struct BlockedRule;
+
+#[async_trait]
+impl Rule for BlockedRule {
+    fn name(&self) -> &'static str {
+        "blocked"
+    }
+
+    fn local(&self) -> bool {
+        true
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        let banned = context.beerlover.banned_accounts();
+
+        Ok((banned.contains(&post.author) || banned.contains(&post.parent_author)).then_some(StakingQueueAction::Blocked))
+    }
+}
+
+/// The comment contains a banned word
+pub struct BannedWordsRule;
+
+#[async_trait]
+impl Rule for BannedWordsRule {
+    fn name(&self) -> &'static str {
+        "banned_words"
+    }
+
+    fn local(&self) -> bool {
+        true
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        let words = context.beerlover.banned_words().find(&post.text);
+        if words.is_empty() {
+            return Ok(None);
+        }
+
+        debug!("Banned words in @{}/{}: {}", post.author, post.permlink, words.join(","));
+        Ok(Some(StakingQueueAction::BlockedWord))
+    }
+}
+
+/// The author rewards themselves or the comment is a root post
+pub struct SelfRewardRule;
+
+#[async_trait]
+impl Rule for SelfRewardRule {
+    fn name(&self) -> &'static str {
+        "self_reward"
+    }
+
+    fn local(&self) -> bool {
+        true
+    }
+
+    async fn check(&self, post: &HivePost, _context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        Ok((post.author == post.parent_author || post.parent_permlink.is_empty() || post.parent_author.is_empty()).then_some(StakingQueueAction::SelfReward))
+    }
+}
+
+/// The command was added by editing a comment created in an earlier block
+pub struct EditedRule;
+
+#[async_trait]
+impl Rule for EditedRule {
+    fn name(&self) -> &'static str {
+        "edited"
+    }
+
+    fn recheck(&self) -> bool {
+        false
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        Ok(match context.hive.get_comment_created(post.author.clone(), post.permlink.clone()).await? {
+            Some(created) if created < context.block.time()? => Some(StakingQueueAction::Edited),
+            _ => None
+        })
+    }
+}
+
+/// The author does not stake enough token for a single share
+pub struct StakeRule;
+
+#[async_trait]
+impl Rule for StakeRule {
+    fn name(&self) -> &'static str {
+        "stake"
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        let balance = context.token_balance(&post.author).await?;
+
+        Ok((context.beerlover.maxium_shares(&balance) <= 0).then_some(StakingQueueAction::NotEnoughStake))
+    }
+}
+
+/// The reward would exceed the shares of the author in the last 24 hours
+pub struct SharesRule;
+
+#[async_trait]
+impl Rule for SharesRule {
+    fn name(&self) -> &'static str {
+        "shares"
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        let balance = context.token_balance(&post.author).await?;
+        let max_shares = context.beerlover.maxium_shares(&balance);
+
+        let share_count = context.database.transferred_shares(post.author.clone()).await?;
+        let pending_share_count = context.database.pending_shares(post.author.clone()).await? + context.pending.shares.get(&post.author).unwrap_or(&0);
+
+        Ok((share_count + pending_share_count + post.shares > max_shares).then_some(StakingQueueAction::SharesExceeded))
+    }
+}
+
+/// The author used the command more often than its daily limit allows
+pub struct DailyLimitRule;
+
+#[async_trait]
+impl Rule for DailyLimitRule {
+    fn name(&self) -> &'static str {
+        "daily_limit"
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        let limit = match context.beerlover.command(&post.command).and_then(|c| c.daily_limit) {
+            Some(limit) => limit,
+            None => return Ok(None)
+        };
+
+        let key = (post.author.clone(), post.command.clone());
+        let count = context.database.command_count(post.author.clone(), post.command.clone()).await? + context.pending.commands.get(&key).unwrap_or(&0);
+
+        Ok((count >= limit).then_some(StakingQueueAction::DailyLimitExceeded))
+    }
+}
+
+/// The reward would exceed the amount the recipient may receive in 24 hours
+pub struct RecipientLimitRule {
+    max_amount: TokenAmount,
+}
+
+#[async_trait]
+impl Rule for RecipientLimitRule {
+    fn name(&self) -> &'static str {
+        "recipient_limit"
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        // Transfers stored before the amount was recorded used the global reward amount
+        let received = context.database.received_amount(post.parent_author.clone(), context.args.reward_amount).await? + context.pending.received.get(&post.parent_author).copied().unwrap_or_default();
+
+        Ok((received + post.amount > self.max_amount).then_some(StakingQueueAction::RecipientLimitExceeded))
+    }
+}
+
+/// The author rewarded the recipient too often in 24 hours
+pub struct PairLimitRule {
+    max_rewards: i64,
+}
+
+#[async_trait]
+impl Rule for PairLimitRule {
+    fn name(&self) -> &'static str {
+        "pair_limit"
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        let key = (post.author.clone(), post.parent_author.clone());
+        let count = context.database.pair_count(post.author.clone(), post.parent_author.clone()).await? + context.pending.pairs.get(&key).unwrap_or(&0);
+
+        Ok((count >= self.max_rewards).then_some(StakingQueueAction::PairLimitExceeded))
+    }
+}
+
+/// The recipient opted out with a `beerlover_optout` custom_json
+pub struct OptOutRule;
+
+#[async_trait]
+impl Rule for OptOutRule {
+    fn name(&self) -> &'static str {
+        "opt_out"
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        Ok(context.database.is_opted_out(post.parent_author.clone()).await?.then_some(StakingQueueAction::OptedOut))
+    }
+}
+
+/// The author is not on the allow-list
+pub struct AllowListRule;
+
+#[async_trait]
+impl Rule for AllowListRule {
+    fn name(&self) -> &'static str {
+        "allow_list"
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        Ok((!context.database.is_allowed(post.author.clone()).await?).then_some(StakingQueueAction::NotAllowed))
+    }
+}
+
+/// The bot account cannot pay the reward on top of the rewards already waiting in the queue.
+/// New rewards line up behind the unfunded ones, which are retried oldest first after a refill.
+pub struct BalanceRule {
+    dry: AtomicBool,
+}
+
+#[async_trait]
+impl Rule for BalanceRule {
+    fn name(&self) -> &'static str {
+        "balance"
+    }
+
+    fn reserves(&self) -> bool {
+        true
+    }
+
+    async fn check(&self, post: &HivePost, context: &mut RuleContext<'_>) -> Result<Option<StakingQueueAction>> {
+        let balance = context.balance().await?;
+        let mut pending = context.database.pending_amount(context.args.reward_amount).await? + context.pending.amount;
+        if !context.retrying {
+            pending += context.database.unfunded_amount(context.args.reward_amount).await? + context.pending.unfunded;
+        }
+
+        if pending + post.amount > balance {
+            if !self.dry.swap(true, Ordering::SeqCst) {
+                warn!("@{} needs a refill: {} {} liquid, {} {} pending", context.args.hive_account, balance, context.args.he_token_symbol, pending, context.args.he_token_symbol);
+            }
+            return Ok(Some(StakingQueueAction::NotEnoughTokenInAccount));
+        }
+
+        if self.dry.swap(false, Ordering::SeqCst) {
+            info!("@{} was refilled, resuming rewards: {} {} liquid", context.args.hive_account, balance, context.args.he_token_symbol);
+        }
+
+        Ok(None)
+    }
+}
+
+/// A rule in the `rules` list of the config file, e.g. `{"rule": "daily_limit"}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum RuleConfig {
+    Blocked,
+    BannedWords,
+    SelfReward,
+    Edited,
+    Stake,
+    Shares,
+    DailyLimit,
+    /// `{"rule": "recipient_limit", "max_amount": "1.000"}`
+    RecipientLimit { max_amount: TokenAmount },
+    /// `{"rule": "pair_limit", "max_rewards": 3}`
+    PairLimit { max_rewards: i64 },
+    OptOut,
+    AllowList,
+    Balance,
+}
+
+impl RuleConfig {
+    fn build(&self) -> Box<dyn Rule> {
+        match self {
+            RuleConfig::Blocked => Box::new(BlockedRule),
+            RuleConfig::BannedWords => Box::new(BannedWordsRule),
+            RuleConfig::SelfReward => Box::new(SelfRewardRule),
+            RuleConfig::Edited => Box::new(EditedRule),
+            RuleConfig::Stake => Box::new(StakeRule),
+            RuleConfig::Shares => Box::new(SharesRule),
+            RuleConfig::DailyLimit => Box::new(DailyLimitRule),
+            RuleConfig::RecipientLimit { max_amount } => Box::new(RecipientLimitRule { max_amount: *max_amount }),
+            RuleConfig::PairLimit { max_rewards } => Box::new(PairLimitRule { max_rewards: *max_rewards }),
+            RuleConfig::OptOut => Box::new(OptOutRule),
+            RuleConfig::AllowList => Box::new(AllowListRule),
+            RuleConfig::Balance => Box::new(BalanceRule { dry: AtomicBool::new(false) }),
+        }
+    }
+
+    /// The rules used when the config file has none
+    pub fn defaults(args: &CLIARGS) -> Vec<RuleConfig> {
+        let mut rules = vec![RuleConfig::Blocked, RuleConfig::BannedWords, RuleConfig::SelfReward];
+
+        if args.allow_list {
+            rules.push(RuleConfig::AllowList);
+        }
+
+        rules.push(RuleConfig::OptOut);
+
+        if args.edit_policy == EditPolicy::Ignore {
+            rules.push(RuleConfig::Edited);
+        }
+
+        // The balance comes last so only rewards that pass every other rule reserve token
+        rules.extend([RuleConfig::Stake, RuleConfig::Shares, RuleConfig::DailyLimit, RuleConfig::Balance]);
+        rules
+    }
+}
+
+/// Runs the rules in order. The first rule that rejects a comment decides its action.
+pub struct RuleEngine {
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<Box<dyn Rule>>) -> RuleEngine {
+        RuleEngine {
+            rules
+        }
+    }
+
+    pub fn from_config(config: &[RuleConfig]) -> RuleEngine {
+        RuleEngine::new(config.iter().map(RuleConfig::build).collect())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.rules.iter().map(|rule| rule.name()).collect()
+    }
+
+    pub async fn evaluate(&self, post: &mut HivePost, context: &mut RuleContext<'_>) -> Result<()> {
+        self.run(post, context, |_| true).await
+    }
+
+    /// Checks a queued entry again, e.g. a reward that was rejected while the bot account was empty
+    pub async fn recheck(&self, post: &mut HivePost, context: &mut RuleContext<'_>) -> Result<()> {
+        self.run(post, context, |rule| rule.recheck()).await
+    }
+
+    async fn run(&self, post: &mut HivePost, context: &mut RuleContext<'_>, selected: fn(&dyn Rule) -> bool) -> Result<()> {
+        for rule in self.rules.iter().filter(|rule| selected(rule.as_ref())) {
+            if rule.reserves() && post.action != StakingQueueAction::StakeAndComment {
+                continue;
+            }
+
+            if let Some(reason) = rule.check(post, context).await? {
+                post.reject(reason);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use mongodb::{bson::doc, bson::DateTime, bson::Document, Collection};
+use mongodb::options::UpdateOptions;
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::mongo::{Database, StakingQueueEntry};
+
+/// Persists the last processed block
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    /// Returns the next block to process, 1 when there is no checkpoint yet
+    async fn get_start_block(&self) -> Result<i64>;
+    async fn set_start_block(&self, block: i64) -> Result<()>;
+
+    /// Stores the queue entries of a block and moves the checkpoint past it
+    async fn commit_block(&self, database: &Database, entries: Vec<StakingQueueEntry>, block: i64) -> Result<()> {
+        database.commit_block(entries, None).await?;
+        self.set_start_block(block).await
+    }
+}
+
+pub struct FileStateStore {
+    path: PathBuf,
+}
+
+impl FileStateStore {
+    pub fn new(path: PathBuf) -> FileStateStore {
+        FileStateStore {
+            path
+        }
+    }
+
+    fn read(&self) -> Result<Option<i64>> {
+        if !Path::new(&self.path).exists() {
+            return Ok(None);
+        }
+
+        let state = fs::read_to_string(&self.path).map_err(|e| Error::StateFile(format!("{}: {}", self.path.display(), e)))?;
+
+        match state.trim().parse::<i64>() {
+            Ok(block) => Ok(Some(block)),
+            Err(e) => Err(Error::StateFile(format!("Invalid block number {:?} in {}: {}", state, self.path.display(), e)))
+        }
+    }
+}
+
+#[async_trait]
+impl StateStore for FileStateStore {
+    async fn get_start_block(&self) -> Result<i64> {
+        Ok(self.read()?.map(|block| block + 1).unwrap_or(1))
+    }
+
+    async fn set_start_block(&self, block: i64) -> Result<()> {
+        // Write to a temporary file first so a crash never leaves a truncated checkpoint
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, block.to_string()).map_err(|e| Error::StateFile(format!("{}: {}", tmp.display(), e)))?;
+        fs::rename(&tmp, &self.path).map_err(|e| Error::StateFile(format!("{}: {}", self.path.display(), e)))
+    }
+}
+
+pub fn state_update(block: i64) -> Document {
+    doc! {
+        "$set": {
+            "block": block,
+            "updatedAt": DateTime::now()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockState {
+    #[serde(rename = "_id")]
+    pub instance: String,
+    pub block: i64,
+    pub updatedAt: DateTime,
+}
+
+pub struct MongoStateStore {
+    collection: Collection<BlockState>,
+    instance: String,
+    legacy: Option<FileStateStore>,
+}
+
+impl MongoStateStore {
+    /// `legacy` is read when the instance has no checkpoint in MongoDB yet,
+    /// so switching from the file backend continues where it stopped.
+    pub fn new(collection: Collection<BlockState>, instance: String, legacy: Option<FileStateStore>) -> MongoStateStore {
+        MongoStateStore {
+            collection,
+            instance,
+            legacy,
+        }
+    }
+}
+
+#[async_trait]
+impl StateStore for MongoStateStore {
+    async fn get_start_block(&self) -> Result<i64> {
+        match self.collection.find_one(doc! { "_id": &self.instance }, None).await? {
+            Some(state) => Ok(state.block + 1),
+            None => match &self.legacy {
+                Some(legacy) => {
+                    let start = legacy.get_start_block().await?;
+                    if start > 1 {
+                        info!("Continuing from {} checkpoint at block {}", legacy.path.display(), start - 1);
+                    }
+                    Ok(start)
+                }
+                None => Ok(1)
+            }
+        }
+    }
+
+    async fn set_start_block(&self, block: i64) -> Result<()> {
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.collection.update_one(doc! {
+            "_id": &self.instance
+        }, state_update(block), options).await?;
+
+        Ok(())
+    }
+
+    async fn commit_block(&self, database: &Database, entries: Vec<StakingQueueEntry>, block: i64) -> Result<()> {
+        database.commit_block(entries, Some((&self.instance, block))).await
+    }
+}